@@ -1,8 +1,11 @@
-use std::{io, time};
+use std::{cmp, hash, io, str, time};
+use std::borrow::Cow;
+use std::io::{Read, Write};
 
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 
-use super::{Pair, DecodeResult, EncodeResult, DecodeError};
+use super::{Pair, DecodeResult, EncodeResult, DecodeError, DecodeErrorKind, EncodeError,
+            EncodeErrorKind, PathSegment, CountingReader, CountingWriter, float_order_key};
 use super::amf3;
 
 #[allow(non_snake_case)]
@@ -28,7 +31,7 @@ mod Marker {
 }
 
 
-#[derive(Debug, Clone, PartialEq, PartialOrd)]
+#[derive(Debug, Clone)]
 pub enum Value {
     Number(f64),
     Boolean(bool),
@@ -41,16 +44,112 @@ pub enum Value {
     Undefined,
     EcmaArray { pairs: Vec<Pair<String, Value>> },
     Array { values: Vec<Value> },
-    Date { unixtime: time::Duration },
+    Date { unixtime: time::Duration, time_zone: i16 },
     LongString(String),
     XmlDoc(String),
     AvmPlus(amf3::Value),
 }
 
+impl Value {
+    // Stable discriminant rank, used to order/hash values of different variants.
+    fn rank(&self) -> u8 {
+        match *self {
+            Value::Number(_) => 0,
+            Value::Boolean(_) => 1,
+            Value::String(_) => 2,
+            Value::Object { .. } => 3,
+            Value::Null => 4,
+            Value::Undefined => 5,
+            Value::EcmaArray { .. } => 6,
+            Value::Array { .. } => 7,
+            Value::Date { .. } => 8,
+            Value::LongString(_) => 9,
+            Value::XmlDoc(_) => 10,
+            Value::AvmPlus(_) => 11,
+        }
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == cmp::Ordering::Equal
+    }
+}
+
+impl Eq for Value {}
+
+impl PartialOrd for Value {
+    fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Value {
+    fn cmp(&self, other: &Self) -> cmp::Ordering {
+        match (self, other) {
+            (&Value::Number(a), &Value::Number(b)) => {
+                float_order_key(a).cmp(&float_order_key(b))
+            }
+            (&Value::Boolean(a), &Value::Boolean(b)) => a.cmp(&b),
+            (&Value::String(ref a), &Value::String(ref b)) => a.cmp(b),
+            (&Value::Object {
+                 name: ref an,
+                 pairs: ref ap,
+             },
+             &Value::Object {
+                 name: ref bn,
+                 pairs: ref bp,
+             }) => an.cmp(bn).then_with(|| ap.cmp(bp)),
+            (&Value::Null, &Value::Null) => cmp::Ordering::Equal,
+            (&Value::Undefined, &Value::Undefined) => cmp::Ordering::Equal,
+            (&Value::EcmaArray { pairs: ref a }, &Value::EcmaArray { pairs: ref b }) => a.cmp(b),
+            (&Value::Array { values: ref a }, &Value::Array { values: ref b }) => a.cmp(b),
+            (&Value::Date { unixtime: a, time_zone: atz },
+             &Value::Date { unixtime: b, time_zone: btz }) => a.cmp(&b).then_with(|| atz.cmp(&btz)),
+            (&Value::LongString(ref a), &Value::LongString(ref b)) => a.cmp(b),
+            (&Value::XmlDoc(ref a), &Value::XmlDoc(ref b)) => a.cmp(b),
+            (&Value::AvmPlus(ref a), &Value::AvmPlus(ref b)) => a.cmp(b),
+            (a, b) => a.rank().cmp(&b.rank()),
+        }
+    }
+}
+
+impl hash::Hash for Value {
+    fn hash<H: hash::Hasher>(&self, state: &mut H) {
+        self.rank().hash(state);
+        match *self {
+            Value::Number(n) => float_order_key(n).hash(state),
+            Value::Boolean(b) => b.hash(state),
+            Value::String(ref s) => s.hash(state),
+            Value::Object {
+                ref name,
+                ref pairs,
+            } => {
+                name.hash(state);
+                pairs.hash(state);
+            }
+            Value::Null | Value::Undefined => {}
+            Value::EcmaArray { ref pairs } => pairs.hash(state),
+            Value::Array { ref values } => values.hash(state),
+            Value::Date { unixtime, time_zone } => {
+                unixtime.hash(state);
+                time_zone.hash(state);
+            }
+            Value::LongString(ref s) => s.hash(state),
+            Value::XmlDoc(ref s) => s.hash(state),
+            Value::AvmPlus(ref v) => v.hash(state),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Decoder<R> {
-    reader: R,
+    reader: CountingReader<R>,
     objects: Vec<Value>,
+    max_depth: usize,
+    max_collection_len: usize,
+    depth: usize,
+    path: Vec<PathSegment>,
 }
 
 impl<R> Decoder<R>
@@ -59,14 +158,107 @@ where
 {
     pub fn new(reader: R) -> Self {
         Decoder {
-            reader: reader,
+            reader: CountingReader::new(reader),
             objects: Vec::new(),
+            max_depth: usize::max_value(),
+            max_collection_len: usize::max_value(),
+            depth: 0,
+            path: Vec::new(),
+        }
+    }
+
+    /// The number of bytes consumed from the underlying reader so far,
+    /// i.e. the absolute offset an error occurring right now would carry.
+    pub fn offset(&self) -> u64 {
+        self.reader.offset()
+    }
+
+    /// Bounds how deeply objects/arrays may nest while decoding, guarding
+    /// against stack exhaustion from hostile input. Unlimited by default.
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// Bounds the element/associative-pair count a single array or object
+    /// may declare, guarding against memory exhaustion from a forged count
+    /// field. Unlimited by default.
+    pub fn with_max_collection_len(mut self, max_collection_len: usize) -> Self {
+        self.max_collection_len = max_collection_len;
+        self
+    }
+
+    fn enter_nested(&mut self) -> DecodeResult<()> {
+        self.depth += 1;
+        if self.depth > self.max_depth {
+            return Err(DecodeErrorKind::DepthLimitExceeded { limit: self.max_depth }.into());
+        }
+        Ok(())
+    }
+
+    fn exit_nested(&mut self) {
+        self.depth -= 1;
+    }
+
+    fn check_collection_len(&self, len: usize) -> DecodeResult<()> {
+        if len > self.max_collection_len {
+            return Err(DecodeErrorKind::CollectionTooLarge { len: len }.into());
         }
+        Ok(())
     }
 
     pub fn decode(&mut self) -> DecodeResult<Value> {
         self.objects.clear();
-        self.decode_value()
+        self.decode_value().map_err(|e| e.with_offset(self.reader.offset()))
+    }
+
+    /// Decodes the next value without resetting the reference table, so a
+    /// `Marker::REFERENCE` can resolve against an object decoded from an
+    /// earlier value in the same logical AMF0 stream. Call
+    /// `clear_reference_table` to start a fresh scope.
+    pub fn decode_next(&mut self) -> DecodeResult<Value> {
+        self.decode_value().map_err(|e| e.with_offset(self.reader.offset()))
+    }
+
+    /// Clears the object reference table, as `decode` does implicitly.
+    pub fn clear_reference_table(&mut self) {
+        self.objects.clear();
+    }
+
+    /// Repeatedly decodes values sharing one reference-table scope until the
+    /// reader reaches a clean end-of-stream (EOF before any marker byte is
+    /// read). A truncated value in the middle of the stream is still
+    /// surfaced as an error rather than silently ending the list, since RTMP
+    /// command payloads commonly carry several AMF0 values back-to-back.
+    pub fn decode_all(&mut self) -> DecodeResult<Vec<Value>> {
+        self.objects.clear();
+        let mut values = Vec::new();
+        loop {
+            let marker = match self.reader.read_u8() {
+                Ok(m) => m,
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(DecodeError::from(e).with_offset(self.reader.offset())),
+            };
+            let value = try!(self.decode_value_from_marker(marker)
+                .map_err(|e| e.with_offset(self.reader.offset())));
+            values.push(value);
+        }
+        Ok(values)
+    }
+
+    /// Consumes the `Decoder`, returning the underlying reader.
+    pub fn into_inner(self) -> R {
+        self.reader.into_inner()
+    }
+
+    /// Gives a reference to the underlying reader.
+    pub fn inner(&self) -> &R {
+        self.reader.inner()
+    }
+
+    /// Gives a mutable reference to the underlying reader.
+    pub fn inner_mut(&mut self) -> &mut R {
+        self.reader.inner_mut()
     }
 
     fn read_utf8(&mut self, len: usize) -> DecodeResult<String> {
@@ -81,14 +273,17 @@ where
         loop {
             let len = try!(self.reader.read_u16::<BigEndian>()) as usize;
             let key = try!(self.read_utf8(len));
-            match self.decode_value() {
+            self.path.push(PathSegment::Key(key.clone()));
+            let result = self.decode_value().map_err(|e| e.with_path(&self.path));
+            self.path.pop();
+            match result {
                 Ok(val) => {
                     v.push(Pair {
                         key: key,
                         value: val,
                     });
                 }
-                Err(DecodeError::NotExpectedObjectEnd) if key.is_empty() => break,
+                Err(DecodeError { kind: DecodeErrorKind::NotExpectedObjectEnd, .. }) if key.is_empty() => break,
                 Err(e) => return Err(e),
             }
         }
@@ -122,17 +317,20 @@ where
 
     fn decode_date(&mut self) -> DecodeResult<Value> {
         let ms = try!(self.reader.read_f64::<BigEndian>());
-        try!(self.reader.read_i16::<BigEndian>()); // skip timezone
+        let time_zone = try!(self.reader.read_i16::<BigEndian>());
         Ok(Value::Date {
             unixtime: time::Duration::from_millis(ms as u64),
+            time_zone: time_zone,
         })
     }
 
     fn decode_object(&mut self) -> DecodeResult<Value> {
-        let pairs = try!(self.decode_pairs());
+        try!(self.enter_nested());
+        let pairs = self.decode_pairs();
+        self.exit_nested();
         let value = Value::Object {
             name: None,
-            pairs: pairs,
+            pairs: try!(pairs),
         };
 
         let index = self.objects.len();
@@ -143,9 +341,12 @@ where
     }
 
     fn decode_ecma_array(&mut self) -> DecodeResult<Value> {
-        try!(self.reader.read_u32::<BigEndian>()) as usize; // skip count
-        let pairs = try!(self.decode_pairs());
-        let value = Value::EcmaArray { pairs: pairs };
+        let count = try!(self.reader.read_u32::<BigEndian>()) as usize; // associative-count
+        try!(self.check_collection_len(count));
+        try!(self.enter_nested());
+        let pairs = self.decode_pairs();
+        self.exit_nested();
+        let value = Value::EcmaArray { pairs: try!(pairs) };
 
         let index = self.objects.len();
         self.objects.push(Value::Null); // 空の値を入れておく
@@ -156,8 +357,27 @@ where
 
     fn decode_strict_array(&mut self) -> DecodeResult<Value> {
         let c = try!(self.reader.read_u32::<BigEndian>()) as usize;
-        let pairs = try!((0..c).map(|_| self.decode_value()).collect());
-        let value = Value::Array { values: pairs };
+        try!(self.check_collection_len(c));
+        try!(self.enter_nested());
+        let mut values = Vec::with_capacity(c);
+        let mut err = None;
+        for i in 0..c {
+            self.path.push(PathSegment::Index(i));
+            let result = self.decode_value().map_err(|e| e.with_path(&self.path));
+            self.path.pop();
+            match result {
+                Ok(v) => values.push(v),
+                Err(e) => {
+                    err = Some(e);
+                    break;
+                }
+            }
+        }
+        self.exit_nested();
+        if let Some(e) = err {
+            return Err(e);
+        }
+        let value = Value::Array { values: values };
 
         let index = self.objects.len();
         self.objects.push(Value::Null); // 空の値を入れておく
@@ -169,10 +389,12 @@ where
     fn decode_typed_object(&mut self) -> DecodeResult<Value> {
         let len = try!(self.reader.read_u16::<BigEndian>()) as usize;
         let name = try!(self.read_utf8(len));
-        let pairs = try!(self.decode_pairs());
+        try!(self.enter_nested());
+        let pairs = self.decode_pairs();
+        self.exit_nested();
         let value = Value::Object {
             name: Some(name),
-            pairs: pairs,
+            pairs: try!(pairs),
         };
 
         let index = self.objects.len();
@@ -187,7 +409,7 @@ where
         let index = try!(self.reader.read_u16::<BigEndian>()) as usize;
         self.objects
             .get(index)
-            .ok_or(DecodeError::NotFoundInReferenceTable { index: index })
+            .ok_or(DecodeErrorKind::NotFoundInReferenceTable { index: index }.into())
             .and_then(|v| Ok(v.clone()))
     }
 
@@ -198,6 +420,10 @@ where
 
     fn decode_value(&mut self) -> DecodeResult<Value> {
         let marker = try!(self.reader.read_u8());
+        self.decode_value_from_marker(marker)
+    }
+
+    fn decode_value_from_marker(&mut self, marker: u8) -> DecodeResult<Value> {
         match marker {
             Marker::NUMBER => self.decode_number(),
             Marker::BOOLEAN => self.decode_boolean(),
@@ -214,19 +440,324 @@ where
             Marker::NULL => Ok(Value::Null),
             Marker::UNDEFINED => Ok(Value::Undefined),
 
-            Marker::OBJECT_END => Err(DecodeError::NotExpectedObjectEnd),
-            Marker::UNSUPPORTED => Err(DecodeError::NotSupportedType { marker }),
-            Marker::RECORDSET => Err(DecodeError::NotSupportedType { marker }),
-            Marker::MOVIECLIP => Err(DecodeError::NotSupportedType { marker }),
+            Marker::OBJECT_END => Err(DecodeErrorKind::NotExpectedObjectEnd.into()),
+            Marker::UNSUPPORTED => Err(DecodeErrorKind::NotSupportedType { marker }.into()),
+            Marker::RECORDSET => Err(DecodeErrorKind::NotSupportedType { marker }.into()),
+            Marker::MOVIECLIP => Err(DecodeErrorKind::NotSupportedType { marker }.into()),
+
+            _ => Err(DecodeErrorKind::UnknownType { marker }.into()),
+        }
+    }
+}
+
+/// Borrowed counterpart of [`Value`], returned by [`read_borrowed`]. Its
+/// string-bearing variants hold `Cow<'a, str>` slices into the source
+/// buffer rather than freshly allocated `String`s, so decoding a high-volume
+/// RTMP command payload doesn't allocate once per string. Decoded strings
+/// are always `Cow::Borrowed`; bytes that aren't valid UTF-8 fail with
+/// `DecodeErrorKind::String`, as they do in `Decoder`. Call `.to_owned()` to
+/// lift a `ValueRef` into an ordinary `Value`
+/// once it needs to outlive `buf`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ValueRef<'a> {
+    Number(f64),
+    Boolean(bool),
+    String(Cow<'a, str>),
+    Object {
+        name: Option<Cow<'a, str>>,
+        pairs: Vec<Pair<Cow<'a, str>, ValueRef<'a>>>,
+    },
+    Null,
+    Undefined,
+    EcmaArray { pairs: Vec<Pair<Cow<'a, str>, ValueRef<'a>>> },
+    Array { values: Vec<ValueRef<'a>> },
+    Date { unixtime: time::Duration, time_zone: i16 },
+    LongString(Cow<'a, str>),
+    XmlDoc(Cow<'a, str>),
+    // Nested AMF3 payloads (the `avmplus` marker) are decoded eagerly into
+    // an owned `amf3::Value`, since zero-copy support for that sub-format is
+    // out of scope here.
+    AvmPlus(amf3::Value),
+}
+
+impl<'a> ValueRef<'a> {
+    /// Lifts this borrowed value into an owned [`Value`], copying any
+    /// strings that are still borrowed from the source buffer.
+    pub fn to_owned(&self) -> Value {
+        match *self {
+            ValueRef::Number(n) => Value::Number(n),
+            ValueRef::Boolean(b) => Value::Boolean(b),
+            ValueRef::String(ref s) => Value::String(s.clone().into_owned()),
+            ValueRef::Object { ref name, ref pairs } => {
+                Value::Object {
+                    name: name.as_ref().map(|n| n.clone().into_owned()),
+                    pairs: pairs
+                        .iter()
+                        .map(|p| {
+                            Pair {
+                                key: p.key.clone().into_owned(),
+                                value: p.value.to_owned(),
+                            }
+                        })
+                        .collect(),
+                }
+            }
+            ValueRef::Null => Value::Null,
+            ValueRef::Undefined => Value::Undefined,
+            ValueRef::EcmaArray { ref pairs } => {
+                Value::EcmaArray {
+                    pairs: pairs
+                        .iter()
+                        .map(|p| {
+                            Pair {
+                                key: p.key.clone().into_owned(),
+                                value: p.value.to_owned(),
+                            }
+                        })
+                        .collect(),
+                }
+            }
+            ValueRef::Array { ref values } => {
+                Value::Array { values: values.iter().map(|v| v.to_owned()).collect() }
+            }
+            ValueRef::Date { unixtime, time_zone } => {
+                Value::Date {
+                    unixtime: unixtime,
+                    time_zone: time_zone,
+                }
+            }
+            ValueRef::LongString(ref s) => Value::LongString(s.clone().into_owned()),
+            ValueRef::XmlDoc(ref s) => Value::XmlDoc(s.clone().into_owned()),
+            ValueRef::AvmPlus(ref v) => Value::AvmPlus(v.clone()),
+        }
+    }
+}
+
+/// Decodes a single AMF0 value out of `buf` without allocating a `String`
+/// per string-bearing field, in the spirit of zero-copy formats like
+/// `rkyv`. Object/array back-references (`Marker::REFERENCE`) are resolved
+/// against a reference table scoped to this one call, matching `Decoder`'s
+/// own `decode` (as opposed to `decode_next`). Unbounded recursion/collection
+/// limits, same as a bare `Decoder::new`; use [`BorrowedDecoder`] to bound
+/// them against untrusted input.
+pub fn read_borrowed<'a>(buf: &'a [u8]) -> DecodeResult<ValueRef<'a>> {
+    BorrowedDecoder::new(buf).decode()
+}
+
+/// Builder for [`read_borrowed`] that can bound recursion depth and
+/// collection size, mirroring `Decoder::with_max_depth`/`with_max_collection_len`
+/// for this zero-copy entry point.
+pub struct BorrowedDecoder<'a> {
+    buf: &'a [u8],
+    max_depth: usize,
+    max_collection_len: usize,
+}
+
+impl<'a> BorrowedDecoder<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        BorrowedDecoder {
+            buf: buf,
+            max_depth: usize::max_value(),
+            max_collection_len: usize::max_value(),
+        }
+    }
+
+    /// Bounds how deeply objects/arrays may nest while decoding, guarding
+    /// against stack exhaustion from hostile input. Unlimited by default.
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// Bounds the element/associative-pair count a single array or object
+    /// may declare, guarding against memory exhaustion from a forged count
+    /// field. Unlimited by default.
+    pub fn with_max_collection_len(mut self, max_collection_len: usize) -> Self {
+        self.max_collection_len = max_collection_len;
+        self
+    }
+
+    pub fn decode(self) -> DecodeResult<ValueRef<'a>> {
+        BorrowedCursor {
+            remaining: self.buf,
+            objects: Vec::new(),
+            max_depth: self.max_depth,
+            max_collection_len: self.max_collection_len,
+            depth: 0,
+        }.decode_value()
+    }
+}
+
+struct BorrowedCursor<'a> {
+    remaining: &'a [u8],
+    objects: Vec<ValueRef<'a>>,
+    max_depth: usize,
+    max_collection_len: usize,
+    depth: usize,
+}
+
+impl<'a> BorrowedCursor<'a> {
+    fn enter_nested(&mut self) -> DecodeResult<()> {
+        self.depth += 1;
+        if self.depth > self.max_depth {
+            return Err(DecodeErrorKind::DepthLimitExceeded { limit: self.max_depth }.into());
+        }
+        Ok(())
+    }
+
+    fn exit_nested(&mut self) {
+        self.depth -= 1;
+    }
+
+    fn check_collection_len(&self, len: usize) -> DecodeResult<()> {
+        if len > self.max_collection_len {
+            return Err(DecodeErrorKind::CollectionTooLarge { len: len }.into());
+        }
+        Ok(())
+    }
+
+    fn take(&mut self, len: usize) -> DecodeResult<&'a [u8]> {
+        if len > self.remaining.len() {
+            return Err(DecodeError::from(io::Error::from(io::ErrorKind::UnexpectedEof)));
+        }
+        let (head, tail) = self.remaining.split_at(len);
+        self.remaining = tail;
+        Ok(head)
+    }
+
+    fn read_str(&mut self, len: usize) -> DecodeResult<Cow<'a, str>> {
+        let bytes = try!(self.take(len));
+        match str::from_utf8(bytes) {
+            Ok(s) => Ok(Cow::Borrowed(s)),
+            Err(_) => Ok(Cow::Owned(try!(String::from_utf8(bytes.to_vec())))),
+        }
+    }
+
+    fn decode_pairs(&mut self) -> DecodeResult<Vec<Pair<Cow<'a, str>, ValueRef<'a>>>> {
+        let mut v = Vec::new();
+        loop {
+            let len = try!(self.remaining.read_u16::<BigEndian>()) as usize;
+            let key = try!(self.read_str(len));
+            match self.decode_value() {
+                Ok(val) => {
+                    v.push(Pair {
+                        key: key,
+                        value: val,
+                    });
+                }
+                Err(DecodeError { kind: DecodeErrorKind::NotExpectedObjectEnd, .. }) if key.is_empty() => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(v)
+    }
+
+    fn decode_value(&mut self) -> DecodeResult<ValueRef<'a>> {
+        let marker = try!(self.remaining.read_u8());
+        match marker {
+            Marker::NUMBER => Ok(ValueRef::Number(try!(self.remaining.read_f64::<BigEndian>()))),
+            Marker::BOOLEAN => Ok(ValueRef::Boolean(try!(self.remaining.read_u8()) != 0)),
+            Marker::STRING => {
+                let len = try!(self.remaining.read_u16::<BigEndian>()) as usize;
+                Ok(ValueRef::String(try!(self.read_str(len))))
+            }
+            Marker::LONG_STRING => {
+                let len = try!(self.remaining.read_u32::<BigEndian>()) as usize;
+                Ok(ValueRef::LongString(try!(self.read_str(len))))
+            }
+            Marker::XML_DOC => {
+                let len = try!(self.remaining.read_u32::<BigEndian>()) as usize;
+                Ok(ValueRef::XmlDoc(try!(self.read_str(len))))
+            }
+            Marker::DATE => {
+                let ms = try!(self.remaining.read_f64::<BigEndian>());
+                let time_zone = try!(self.remaining.read_i16::<BigEndian>());
+                Ok(ValueRef::Date {
+                    unixtime: time::Duration::from_millis(ms as u64),
+                    time_zone: time_zone,
+                })
+            }
+            Marker::OBJECT => {
+                try!(self.enter_nested());
+                let pairs = self.decode_pairs();
+                self.exit_nested();
+                let value = ValueRef::Object {
+                    name: None,
+                    pairs: try!(pairs),
+                };
+                let index = self.objects.len();
+                self.objects.push(ValueRef::Null);
+                self.objects[index] = value.clone();
+                Ok(value)
+            }
+            Marker::ECMA_ARRAY => {
+                let count = try!(self.remaining.read_u32::<BigEndian>()) as usize;
+                try!(self.check_collection_len(count));
+                try!(self.enter_nested());
+                let pairs = self.decode_pairs();
+                self.exit_nested();
+                let value = ValueRef::EcmaArray { pairs: try!(pairs) };
+                let index = self.objects.len();
+                self.objects.push(ValueRef::Null);
+                self.objects[index] = value.clone();
+                Ok(value)
+            }
+            Marker::STRICT_ARRAY => {
+                let c = try!(self.remaining.read_u32::<BigEndian>()) as usize;
+                try!(self.check_collection_len(c));
+                try!(self.enter_nested());
+                let values = (0..c).map(|_| self.decode_value()).collect();
+                self.exit_nested();
+                let value = ValueRef::Array { values: try!(values) };
+                let index = self.objects.len();
+                self.objects.push(ValueRef::Null);
+                self.objects[index] = value.clone();
+                Ok(value)
+            }
+            Marker::TYPED_OBJECT => {
+                let len = try!(self.remaining.read_u16::<BigEndian>()) as usize;
+                let name = try!(self.read_str(len));
+                try!(self.enter_nested());
+                let pairs = self.decode_pairs();
+                self.exit_nested();
+                let value = ValueRef::Object {
+                    name: Some(name),
+                    pairs: try!(pairs),
+                };
+                let index = self.objects.len();
+                self.objects.push(ValueRef::Null);
+                self.objects[index] = value.clone();
+                Ok(value)
+            }
+            Marker::REFERENCE => {
+                let index = try!(self.remaining.read_u16::<BigEndian>()) as usize;
+                self.objects
+                    .get(index)
+                    .cloned()
+                    .ok_or(DecodeErrorKind::NotFoundInReferenceTable { index: index }.into())
+            }
+            Marker::AVMPLUS => {
+                let value = try!(amf3::Decoder::new(&mut self.remaining).decode());
+                Ok(ValueRef::AvmPlus(value))
+            }
+            Marker::NULL => Ok(ValueRef::Null),
+            Marker::UNDEFINED => Ok(ValueRef::Undefined),
+
+            Marker::OBJECT_END => Err(DecodeErrorKind::NotExpectedObjectEnd.into()),
+            Marker::UNSUPPORTED => Err(DecodeErrorKind::NotSupportedType { marker }.into()),
+            Marker::RECORDSET => Err(DecodeErrorKind::NotSupportedType { marker }.into()),
+            Marker::MOVIECLIP => Err(DecodeErrorKind::NotSupportedType { marker }.into()),
 
-            _ => Err(DecodeError::UnknownType { marker }),
+            _ => Err(DecodeErrorKind::UnknownType { marker }.into()),
         }
     }
 }
 
 #[derive(Debug)]
 pub struct Encoder<W> {
-    writer: W,
+    writer: CountingWriter<W>,
+    use_references: bool,
+    objects: Vec<Value>,
 }
 
 impl<W> Encoder<W>
@@ -234,22 +765,82 @@ where
     W: io::Write,
 {
     pub fn new(writer: W) -> Self {
-        Encoder { writer: writer }
+        Encoder {
+            writer: CountingWriter::new(writer),
+            use_references: false,
+            objects: Vec::new(),
+        }
+    }
+
+    /// Enables reference-table deduplication: repeated complex values (objects,
+    /// typed objects, ECMA arrays, strict arrays) are emitted as `Marker::REFERENCE`
+    /// back-references instead of being inlined again.
+    pub fn with_references(mut self) -> Self {
+        self.use_references = true;
+        self
+    }
+
+    /// The number of bytes written to the underlying writer so far, i.e. the
+    /// absolute offset an error occurring right now would carry.
+    pub fn offset(&self) -> u64 {
+        self.writer.offset()
     }
 
     pub fn encode(&mut self, value: &Value) -> EncodeResult<()> {
-        self.encode_value(value)
+        self.objects.clear();
+        self.encode_value(value).map_err(|e| e.with_offset(self.writer.offset()))
+    }
+
+    /// Consumes the `Encoder`, returning the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.writer.into_inner()
+    }
+
+    /// Gives a reference to the underlying writer.
+    pub fn inner(&self) -> &W {
+        self.writer.inner()
+    }
+
+    /// Gives a mutable reference to the underlying writer.
+    pub fn inner_mut(&mut self) -> &mut W {
+        self.writer.inner_mut()
+    }
+
+    // Checks the reference table for a structurally-equal complex value.
+    // Returns `Ok(true)` when a `Marker::REFERENCE` was written in place of the
+    // value (the caller must not encode the body), `Ok(false)` otherwise. On a
+    // miss, the value is pushed into the table *before* its body is encoded, so
+    // a container takes a lower index than the complex values nested inside it.
+    fn try_write_reference(&mut self, value: &Value) -> EncodeResult<bool> {
+        if !self.use_references {
+            return Ok(false);
+        }
+
+        if let Some(index) = self.objects.iter().position(|v| v == value) {
+            try!(self.writer.write_u8(Marker::REFERENCE));
+            try!(self.writer.write_u16::<BigEndian>(index as u16));
+            return Ok(true);
+        }
+
+        if self.objects.len() < 0xFFFF {
+            self.objects.push(value.clone());
+        }
+        Ok(false)
     }
 
     fn write_string(&mut self, s: &str) -> EncodeResult<()> {
-        assert!(s.len() <= 0xFFFF);
+        if s.len() > 0xFFFF {
+            return Err(EncodeErrorKind::StringTooLong { len: s.len() }.into());
+        }
         try!(self.writer.write_u16::<BigEndian>(s.len() as u16));
         try!(self.writer.write_all(s.as_bytes()));
         Ok(())
     }
 
     fn write_long_string(&mut self, s: &str) -> EncodeResult<()> {
-        assert!(s.len() <= 0xFFFF_FFFF);
+        if s.len() > 0xFFFF_FFFF {
+            return Err(EncodeErrorKind::StringTooLong { len: s.len() }.into());
+        }
         try!(self.writer.write_u32::<BigEndian>(s.len() as u32));
         try!(self.writer.write_all(s.as_bytes()));
         Ok(())
@@ -277,98 +868,1287 @@ where
         Ok(())
     }
 
-    fn encode_string(&mut self, string: &str) -> EncodeResult<()> {
-        if string.len() <= 0xFFFF {
-            try!(self.writer.write_u8(Marker::STRING));
-            try!(self.write_string(&string));
-        } else {
-            try!(self.writer.write_u8(Marker::LONG_STRING));
-            try!(self.write_long_string(&string));
+    fn encode_string(&mut self, string: &str) -> EncodeResult<()> {
+        if string.len() <= 0xFFFF {
+            try!(self.writer.write_u8(Marker::STRING));
+            try!(self.write_string(&string));
+        } else {
+            try!(self.writer.write_u8(Marker::LONG_STRING));
+            try!(self.write_long_string(&string));
+        }
+        Ok(())
+    }
+
+    fn encode_xml_doc(&mut self, xml_doc: &str) -> EncodeResult<()> {
+        try!(self.writer.write_u8(Marker::XML_DOC));
+        try!(self.write_long_string(&xml_doc));
+        Ok(())
+    }
+
+    fn encode_date(&mut self, unixtime: time::Duration, time_zone: i16) -> EncodeResult<()> {
+        let ms = unixtime.as_secs() * 1000 + (unixtime.subsec_nanos() as u64) / 1000_000;
+        try!(self.writer.write_u8(Marker::DATE));
+        try!(self.writer.write_f64::<BigEndian>(ms as f64));
+        try!(self.writer.write_i16::<BigEndian>(time_zone));
+        Ok(())
+    }
+
+    fn encode_object(
+        &mut self,
+        value: &Value,
+        name: &Option<String>,
+        pairs: &[Pair<String, Value>],
+    ) -> EncodeResult<()> {
+        if try!(self.try_write_reference(value)) {
+            return Ok(());
+        }
+        if let Some(name) = name.as_ref() {
+            try!(self.writer.write_u8(Marker::TYPED_OBJECT));
+            try!(self.write_string(name));
+        } else {
+            try!(self.writer.write_u8(Marker::OBJECT));
+        }
+        try!(self.encode_pairs(pairs));
+        Ok(())
+    }
+
+    fn encode_ecma_array(&mut self, value: &Value, pairs: &[Pair<String, Value>]) -> EncodeResult<()> {
+        if try!(self.try_write_reference(value)) {
+            return Ok(());
+        }
+        if pairs.len() > 0xFFFF_FFFF {
+            return Err(EncodeErrorKind::ArrayTooLong { len: pairs.len() }.into());
+        }
+        try!(self.writer.write_u8(Marker::ECMA_ARRAY));
+        try!(self.writer.write_u32::<BigEndian>(pairs.len() as u32)); // associative-count => u32
+        try!(self.encode_pairs(pairs));
+        Ok(())
+    }
+
+    fn encode_strict_array(&mut self, value: &Value, values: &[Value]) -> EncodeResult<()> {
+        if try!(self.try_write_reference(value)) {
+            return Ok(());
+        }
+        if values.len() > 0xFFFF_FFFF {
+            return Err(EncodeErrorKind::ArrayTooLong { len: values.len() }.into());
+        }
+        try!(self.writer.write_u8(Marker::STRICT_ARRAY));
+        try!(self.writer.write_u32::<BigEndian>(values.len() as u32)); // array-count => u32
+        for v in values {
+            try!(self.encode_value(v));
+        }
+        Ok(())
+    }
+
+    fn encode_avmplus(&mut self, value: &amf3::Value) -> EncodeResult<()> {
+        try!(self.writer.write_u8(Marker::AVMPLUS));
+        try!(amf3::Encoder::new(&mut self.writer).encode(value));
+        Ok(())
+    }
+
+    fn encode_null(&mut self) -> EncodeResult<()> {
+        try!(self.writer.write_u8(Marker::NULL));
+        Ok(())
+    }
+
+    fn encode_undefined(&mut self) -> EncodeResult<()> {
+        try!(self.writer.write_u8(Marker::UNDEFINED));
+        Ok(())
+    }
+
+    fn encode_value(&mut self, value: &Value) -> EncodeResult<()> {
+        match *value {
+            Value::Number(number) => self.encode_number(number),
+            Value::Boolean(boolean) => self.encode_boolean(boolean),
+            Value::String(ref string) => self.encode_string(string),
+            Value::Object {
+                ref name,
+                ref pairs,
+            } => self.encode_object(value, name, pairs),
+            Value::EcmaArray { ref pairs } => self.encode_ecma_array(value, pairs),
+            Value::Array { ref values } => self.encode_strict_array(value, values),
+            Value::Date { unixtime, time_zone } => self.encode_date(unixtime, time_zone),
+            Value::LongString(ref string) => self.encode_string(string),
+            Value::XmlDoc(ref xml_doc) => self.encode_xml_doc(xml_doc),
+            Value::AvmPlus(ref value) => self.encode_avmplus(value),
+            Value::Null => self.encode_null(),
+            Value::Undefined => self.encode_undefined(),
+        }
+    }
+}
+
+/// Bridges `amf0::Value` onto serde's data model: `Number` maps to `f64`,
+/// `Boolean` to `bool`, `String`/`LongString`/`XmlDoc` to `str`, `Array` to a
+/// seq, `Object`/`EcmaArray` to a map, `Null`/`Undefined` to `none`/`unit`,
+/// and `Date` to a newtype carrying the millisecond timestamp. This lets a
+/// user transcode AMF0 to/from any other serde format, or derive their own
+/// structs from a decoded `Value` via `from_value`/`to_value`.
+#[cfg(feature = "serde")]
+pub mod value_serde {
+    use std::fmt;
+
+    use std::io;
+
+    use serde::{Serialize, Serializer, Deserialize, Deserializer};
+    use serde::ser::{self, SerializeMap, SerializeSeq};
+    use serde::de::{self, Visitor, SeqAccess, MapAccess};
+
+    use super::{Pair, Value, Decoder, Encoder};
+
+    #[derive(Debug)]
+    pub struct Error(String);
+
+    impl fmt::Display for Error {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "{}", self.0)
+        }
+    }
+
+    impl ::std::error::Error for Error {
+        fn description(&self) -> &str {
+            &self.0
+        }
+    }
+
+    impl ser::Error for Error {
+        fn custom<T: fmt::Display>(msg: T) -> Self {
+            Error(msg.to_string())
+        }
+    }
+
+    impl de::Error for Error {
+        fn custom<T: fmt::Display>(msg: T) -> Self {
+            Error(msg.to_string())
+        }
+    }
+
+    impl Serialize for Value {
+        fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+        {
+            match *self {
+                Value::Number(n) => serializer.serialize_f64(n),
+                Value::Boolean(b) => serializer.serialize_bool(b),
+                Value::String(ref s) |
+                Value::LongString(ref s) |
+                Value::XmlDoc(ref s) => serializer.serialize_str(s),
+                Value::Null => serializer.serialize_none(),
+                Value::Undefined => serializer.serialize_unit(),
+                Value::Date { unixtime, .. } => {
+                    let ms = unixtime.as_secs() * 1000 +
+                        (unixtime.subsec_nanos() as u64) / 1_000_000;
+                    serializer.serialize_newtype_struct("Date", &ms)
+                }
+                Value::Array { ref values } => {
+                    let mut seq = try!(serializer.serialize_seq(Some(values.len())));
+                    for v in values {
+                        try!(seq.serialize_element(v));
+                    }
+                    seq.end()
+                }
+                Value::EcmaArray { ref pairs } => serialize_pairs(pairs, serializer),
+                Value::Object {
+                    name: None,
+                    ref pairs,
+                } => serialize_pairs(pairs, serializer),
+                Value::Object {
+                    name: Some(ref name),
+                    ref pairs,
+                } => {
+                    let mut map = try!(serializer.serialize_map(Some(1)));
+                    try!(map.serialize_entry(name, &PairsAsMap(pairs)));
+                    map.end()
+                }
+                Value::AvmPlus(ref v) => v.serialize(serializer),
+            }
+        }
+    }
+
+    struct PairsAsMap<'a>(&'a [Pair<String, Value>]);
+
+    impl<'a> Serialize for PairsAsMap<'a> {
+        fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+        {
+            serialize_pairs(self.0, serializer)
+        }
+    }
+
+    fn serialize_pairs<S>(pairs: &[Pair<String, Value>], serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut map = try!(serializer.serialize_map(Some(pairs.len())));
+        for pair in pairs {
+            try!(map.serialize_entry(&pair.key, &pair.value));
+        }
+        map.end()
+    }
+
+    impl<'de> Deserialize<'de> for Value {
+        fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            deserializer.deserialize_any(ValueVisitor)
+        }
+    }
+
+    struct ValueVisitor;
+
+    impl<'de> Visitor<'de> for ValueVisitor {
+        type Value = Value;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a value representable as AMF0")
+        }
+
+        fn visit_bool<E>(self, v: bool) -> Result<Value, E> {
+            Ok(Value::Boolean(v))
+        }
+
+        fn visit_f64<E>(self, v: f64) -> Result<Value, E> {
+            Ok(Value::Number(v))
+        }
+
+        fn visit_i64<E>(self, v: i64) -> Result<Value, E> {
+            Ok(Value::Number(v as f64))
+        }
+
+        fn visit_u64<E>(self, v: u64) -> Result<Value, E> {
+            Ok(Value::Number(v as f64))
+        }
+
+        fn visit_str<E>(self, v: &str) -> Result<Value, E>
+        where
+            E: de::Error,
+        {
+            Ok(Value::String(v.to_string()))
+        }
+
+        fn visit_string<E>(self, v: String) -> Result<Value, E> {
+            Ok(Value::String(v))
+        }
+
+        fn visit_unit<E>(self) -> Result<Value, E> {
+            Ok(Value::Undefined)
+        }
+
+        fn visit_none<E>(self) -> Result<Value, E> {
+            Ok(Value::Null)
+        }
+
+        fn visit_some<D>(self, deserializer: D) -> Result<Value, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            Deserialize::deserialize(deserializer)
+        }
+
+        fn visit_seq<A>(self, mut seq: A) -> Result<Value, A::Error>
+        where
+            A: SeqAccess<'de>,
+        {
+            let mut values = Vec::new();
+            while let Some(v) = try!(seq.next_element()) {
+                values.push(v);
+            }
+            Ok(Value::Array { values: values })
+        }
+
+        fn visit_map<A>(self, mut map: A) -> Result<Value, A::Error>
+        where
+            A: MapAccess<'de>,
+        {
+            let mut pairs = Vec::new();
+            while let Some((key, value)) = try!(map.next_entry::<String, Value>()) {
+                pairs.push(Pair {
+                    key: key,
+                    value: value,
+                });
+            }
+            Ok(Value::Object {
+                name: None,
+                pairs: pairs,
+            })
+        }
+    }
+
+    /// Converts any `T: Serialize` into a `Value`, analogous to `serde_json::to_value`.
+    pub fn to_value<T>(value: T) -> Result<Value, Error>
+    where
+        T: Serialize,
+    {
+        value.serialize(ValueToValueSerializer)
+    }
+
+    /// Converts a decoded `Value` back into any `T: Deserialize`.
+    pub fn from_value<T>(value: Value) -> Result<T, Error>
+    where
+        T: for<'de> Deserialize<'de>,
+    {
+        T::deserialize(value)
+    }
+
+    /// Serializes any `T: Serialize` straight to AMF0 bytes, analogous to
+    /// `serde_json::to_writer`.
+    pub fn to_writer<W, T>(writer: W, value: &T) -> Result<(), Error>
+    where
+        W: io::Write,
+        T: Serialize,
+    {
+        let value = try!(value.serialize(ValueToValueSerializer));
+        Encoder::new(writer).encode(&value).map_err(
+            |e| Error(e.to_string()),
+        )
+    }
+
+    /// Decodes a single AMF0 value from `reader` and converts it into any
+    /// `T: Deserialize`, analogous to `serde_json::from_reader`.
+    pub fn from_reader<R, T>(reader: R) -> Result<T, Error>
+    where
+        R: io::Read,
+        T: for<'de> Deserialize<'de>,
+    {
+        let value = try!(Decoder::new(reader).decode().map_err(
+            |e| Error(e.to_string()),
+        ));
+        from_value(value)
+    }
+
+    // Lets a `Value` feed any `Deserialize` implementation directly, which is
+    // what `from_value` relies on.
+    impl<'de> Deserializer<'de> for Value {
+        type Error = Error;
+
+        fn deserialize_any<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            match self {
+                Value::Number(n) => visitor.visit_f64(n),
+                Value::Boolean(b) => visitor.visit_bool(b),
+                Value::String(s) |
+                Value::LongString(s) |
+                Value::XmlDoc(s) => visitor.visit_string(s),
+                Value::Null => visitor.visit_none(),
+                Value::Undefined => visitor.visit_unit(),
+                Value::Date { unixtime, .. } => {
+                    let ms = unixtime.as_secs() * 1000 +
+                        (unixtime.subsec_nanos() as u64) / 1_000_000;
+                    visitor.visit_u64(ms)
+                }
+                Value::Array { values } => {
+                    visitor.visit_seq(SeqDeserializer { iter: values.into_iter() })
+                }
+                Value::EcmaArray { pairs } |
+                Value::Object { name: _, pairs } => {
+                    visitor.visit_map(PairsDeserializer {
+                        iter: pairs.into_iter(),
+                        value: None,
+                    })
+                }
+                Value::AvmPlus(_) => {
+                    Err(Error("AvmPlus values are not supported by the serde bridge".to_string()))
+                }
+            }
+        }
+
+        fn deserialize_option<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            match self {
+                Value::Null => visitor.visit_none(),
+                other => visitor.visit_some(other),
+            }
+        }
+
+        fn deserialize_bool<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_i8<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_i16<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_i32<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_i64<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_u8<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_u16<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_u32<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_u64<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_f32<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_f64<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_char<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_str<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_string<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_bytes<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_byte_buf<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_unit<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_unit_struct<V>(
+            self,
+            _name: &'static str,
+            visitor: V,
+        ) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_newtype_struct<V>(
+            self,
+            _name: &'static str,
+            visitor: V,
+        ) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            visitor.visit_newtype_struct(self)
+        }
+        fn deserialize_seq<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_tuple<V>(self, _len: usize, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_tuple_struct<V>(
+            self,
+            _name: &'static str,
+            _len: usize,
+            visitor: V,
+        ) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_map<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_struct<V>(
+            self,
+            _name: &'static str,
+            _fields: &'static [&'static str],
+            visitor: V,
+        ) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_enum<V>(
+            self,
+            _name: &'static str,
+            _variants: &'static [&'static str],
+            visitor: V,
+        ) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            match self {
+                Value::String(variant) => {
+                    visitor.visit_enum(EnumDeserializer { variant: variant, value: None })
+                }
+                Value::Object { pairs, .. } |
+                Value::EcmaArray { pairs } => {
+                    let mut iter = pairs.into_iter();
+                    let pair = match iter.next() {
+                        Some(pair) => pair,
+                        None => {
+                            return Err(Error(
+                                "expected a single-entry map representing an enum variant"
+                                    .to_string(),
+                            ))
+                        }
+                    };
+                    visitor.visit_enum(EnumDeserializer { variant: pair.key, value: Some(pair.value) })
+                }
+                other => Err(Error(format!("invalid type: {:?}, expected enum", other))),
+            }
+        }
+        fn deserialize_identifier<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_ignored_any<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+    }
+
+    // Drives `Visitor::visit_enum` off the single-entry `Object`/`EcmaArray`
+    // (or bare `String` for a unit variant) that `serialize_*_variant` below
+    // produces, so enums tagged that way can round-trip back through `from_value`.
+    struct EnumDeserializer {
+        variant: String,
+        value: Option<Value>,
+    }
+
+    impl<'de> de::EnumAccess<'de> for EnumDeserializer {
+        type Error = Error;
+        type Variant = VariantDeserializer;
+
+        fn variant_seed<V>(self, seed: V) -> Result<(V::Value, VariantDeserializer), Error>
+        where
+            V: de::DeserializeSeed<'de>,
+        {
+            let variant = try!(seed.deserialize(StringDeserializer(self.variant)));
+            Ok((variant, VariantDeserializer { value: self.value }))
+        }
+    }
+
+    struct VariantDeserializer {
+        value: Option<Value>,
+    }
+
+    impl<'de> de::VariantAccess<'de> for VariantDeserializer {
+        type Error = Error;
+
+        fn unit_variant(self) -> Result<(), Error> {
+            match self.value {
+                None => Ok(()),
+                Some(value) => Err(Error(format!("invalid type: {:?}, expected unit variant", value))),
+            }
+        }
+
+        fn newtype_variant_seed<T>(self, seed: T) -> Result<T::Value, Error>
+        where
+            T: de::DeserializeSeed<'de>,
+        {
+            match self.value {
+                Some(value) => seed.deserialize(value),
+                None => Err(Error("expected a newtype variant, found a unit variant".to_string())),
+            }
+        }
+
+        fn tuple_variant<V>(self, _len: usize, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            match self.value {
+                Some(Value::Array { values }) => {
+                    visitor.visit_seq(SeqDeserializer { iter: values.into_iter() })
+                }
+                Some(value) => Err(Error(format!("invalid type: {:?}, expected tuple variant", value))),
+                None => Err(Error("expected a tuple variant, found a unit variant".to_string())),
+            }
+        }
+
+        fn struct_variant<V>(
+            self,
+            _fields: &'static [&'static str],
+            visitor: V,
+        ) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            match self.value {
+                Some(Value::Object { pairs, .. }) |
+                Some(Value::EcmaArray { pairs }) => {
+                    visitor.visit_map(PairsDeserializer { iter: pairs.into_iter(), value: None })
+                }
+                Some(value) => Err(Error(format!("invalid type: {:?}, expected struct variant", value))),
+                None => Err(Error("expected a struct variant, found a unit variant".to_string())),
+            }
+        }
+    }
+
+    struct SeqDeserializer {
+        iter: ::std::vec::IntoIter<Value>,
+    }
+
+    impl<'de> SeqAccess<'de> for SeqDeserializer {
+        type Error = Error;
+
+        fn next_element_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>, Error>
+        where
+            T: de::DeserializeSeed<'de>,
+        {
+            match self.iter.next() {
+                Some(v) => seed.deserialize(v).map(Some),
+                None => Ok(None),
+            }
+        }
+    }
+
+    struct PairsDeserializer {
+        iter: ::std::vec::IntoIter<Pair<String, Value>>,
+        value: Option<Value>,
+    }
+
+    impl<'de> MapAccess<'de> for PairsDeserializer {
+        type Error = Error;
+
+        fn next_key_seed<K>(&mut self, seed: K) -> Result<Option<K::Value>, Error>
+        where
+            K: de::DeserializeSeed<'de>,
+        {
+            match self.iter.next() {
+                Some(pair) => {
+                    self.value = Some(pair.value);
+                    seed.deserialize(StringDeserializer(pair.key)).map(Some)
+                }
+                None => Ok(None),
+            }
+        }
+
+        fn next_value_seed<V>(&mut self, seed: V) -> Result<V::Value, Error>
+        where
+            V: de::DeserializeSeed<'de>,
+        {
+            let value = self.value.take().expect(
+                "next_value_seed called before next_key_seed",
+            );
+            seed.deserialize(value)
+        }
+    }
+
+    // A `Deserializer` over a bare `String`, used for object/map keys.
+    struct StringDeserializer(String);
+
+    impl<'de> Deserializer<'de> for StringDeserializer {
+        type Error = Error;
+
+        fn deserialize_any<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            visitor.visit_string(self.0)
+        }
+
+        fn deserialize_option<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            visitor.visit_some(self)
+        }
+
+        fn deserialize_bool<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_i8<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_i16<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_i32<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_i64<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_u8<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_u16<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_u32<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_u64<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_f32<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_f64<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_char<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_str<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_string<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_bytes<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_byte_buf<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_unit<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_unit_struct<V>(
+            self,
+            _name: &'static str,
+            visitor: V,
+        ) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_newtype_struct<V>(
+            self,
+            _name: &'static str,
+            visitor: V,
+        ) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_seq<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_tuple<V>(self, _len: usize, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_tuple_struct<V>(
+            self,
+            _name: &'static str,
+            _len: usize,
+            visitor: V,
+        ) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_map<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_struct<V>(
+            self,
+            _name: &'static str,
+            _fields: &'static [&'static str],
+            visitor: V,
+        ) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_enum<V>(
+            self,
+            _name: &'static str,
+            _variants: &'static [&'static str],
+            visitor: V,
+        ) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_identifier<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_ignored_any<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+    }
+
+    // A minimal `Serializer` whose every method builds a `Value`, mirroring
+    // `serde_json::value::Serializer`.
+    struct ValueToValueSerializer;
+
+    impl Serializer for ValueToValueSerializer {
+        type Ok = Value;
+        type Error = Error;
+        type SerializeSeq = SeqBuilder;
+        type SerializeTuple = SeqBuilder;
+        type SerializeTupleStruct = SeqBuilder;
+        type SerializeTupleVariant = SeqBuilder;
+        type SerializeMap = MapBuilder;
+        type SerializeStruct = MapBuilder;
+        type SerializeStructVariant = MapBuilder;
+
+        fn serialize_bool(self, v: bool) -> Result<Value, Error> {
+            Ok(Value::Boolean(v))
+        }
+        fn serialize_i8(self, v: i8) -> Result<Value, Error> {
+            Ok(Value::Number(v as f64))
+        }
+        fn serialize_i16(self, v: i16) -> Result<Value, Error> {
+            Ok(Value::Number(v as f64))
+        }
+        fn serialize_i32(self, v: i32) -> Result<Value, Error> {
+            Ok(Value::Number(v as f64))
+        }
+        fn serialize_i64(self, v: i64) -> Result<Value, Error> {
+            Ok(Value::Number(v as f64))
+        }
+        fn serialize_u8(self, v: u8) -> Result<Value, Error> {
+            Ok(Value::Number(v as f64))
+        }
+        fn serialize_u16(self, v: u16) -> Result<Value, Error> {
+            Ok(Value::Number(v as f64))
+        }
+        fn serialize_u32(self, v: u32) -> Result<Value, Error> {
+            Ok(Value::Number(v as f64))
+        }
+        fn serialize_u64(self, v: u64) -> Result<Value, Error> {
+            Ok(Value::Number(v as f64))
+        }
+        fn serialize_f32(self, v: f32) -> Result<Value, Error> {
+            Ok(Value::Number(v as f64))
+        }
+        fn serialize_f64(self, v: f64) -> Result<Value, Error> {
+            Ok(Value::Number(v))
+        }
+        fn serialize_char(self, v: char) -> Result<Value, Error> {
+            Ok(Value::String(v.to_string()))
+        }
+        fn serialize_str(self, v: &str) -> Result<Value, Error> {
+            Ok(Value::String(v.to_string()))
+        }
+        fn serialize_bytes(self, v: &[u8]) -> Result<Value, Error> {
+            let values = v.iter().map(|&b| Value::Number(b as f64)).collect();
+            Ok(Value::Array { values: values })
+        }
+        fn serialize_none(self) -> Result<Value, Error> {
+            Ok(Value::Null)
+        }
+        fn serialize_some<T: ?Sized>(self, value: &T) -> Result<Value, Error>
+        where
+            T: Serialize,
+        {
+            value.serialize(self)
+        }
+        fn serialize_unit(self) -> Result<Value, Error> {
+            Ok(Value::Undefined)
+        }
+        fn serialize_unit_struct(self, _name: &'static str) -> Result<Value, Error> {
+            Ok(Value::Undefined)
+        }
+        fn serialize_unit_variant(
+            self,
+            _name: &'static str,
+            _index: u32,
+            variant: &'static str,
+        ) -> Result<Value, Error> {
+            Ok(Value::String(variant.to_string()))
+        }
+        fn serialize_newtype_struct<T: ?Sized>(
+            self,
+            _name: &'static str,
+            value: &T,
+        ) -> Result<Value, Error>
+        where
+            T: Serialize,
+        {
+            value.serialize(self)
+        }
+        fn serialize_newtype_variant<T: ?Sized>(
+            self,
+            _name: &'static str,
+            _index: u32,
+            variant: &'static str,
+            value: &T,
+        ) -> Result<Value, Error>
+        where
+            T: Serialize,
+        {
+            Ok(Value::Object {
+                name: None,
+                pairs: vec![
+                    Pair {
+                        key: variant.to_string(),
+                        value: try!(value.serialize(ValueToValueSerializer)),
+                    },
+                ],
+            })
+        }
+        fn serialize_seq(self, len: Option<usize>) -> Result<SeqBuilder, Error> {
+            Ok(SeqBuilder {
+                values: Vec::with_capacity(len.unwrap_or(0)),
+                variant: None,
+            })
+        }
+        fn serialize_tuple(self, len: usize) -> Result<SeqBuilder, Error> {
+            self.serialize_seq(Some(len))
+        }
+        fn serialize_tuple_struct(
+            self,
+            _name: &'static str,
+            len: usize,
+        ) -> Result<SeqBuilder, Error> {
+            self.serialize_seq(Some(len))
+        }
+        fn serialize_tuple_variant(
+            self,
+            _name: &'static str,
+            _index: u32,
+            variant: &'static str,
+            len: usize,
+        ) -> Result<SeqBuilder, Error> {
+            Ok(SeqBuilder {
+                values: Vec::with_capacity(len),
+                variant: Some(variant),
+            })
+        }
+        fn serialize_map(self, len: Option<usize>) -> Result<MapBuilder, Error> {
+            Ok(MapBuilder {
+                pairs: Vec::with_capacity(len.unwrap_or(0)),
+                next_key: None,
+                variant: None,
+            })
+        }
+        fn serialize_struct(
+            self,
+            _name: &'static str,
+            len: usize,
+        ) -> Result<MapBuilder, Error> {
+            self.serialize_map(Some(len))
+        }
+        fn serialize_struct_variant(
+            self,
+            _name: &'static str,
+            _index: u32,
+            variant: &'static str,
+            len: usize,
+        ) -> Result<MapBuilder, Error> {
+            Ok(MapBuilder {
+                pairs: Vec::with_capacity(len),
+                next_key: None,
+                variant: Some(variant),
+            })
         }
-        Ok(())
     }
 
-    fn encode_xml_doc(&mut self, xml_doc: &str) -> EncodeResult<()> {
-        try!(self.writer.write_u8(Marker::XML_DOC));
-        try!(self.write_long_string(&xml_doc));
-        Ok(())
+    struct SeqBuilder {
+        values: Vec<Value>,
+        variant: Option<&'static str>,
+    }
+
+    impl SeqBuilder {
+        fn into_value(self) -> Value {
+            let array = Value::Array { values: self.values };
+            match self.variant {
+                Some(variant) => {
+                    Value::Object {
+                        name: None,
+                        pairs: vec![
+                            Pair {
+                                key: variant.to_string(),
+                                value: array,
+                            },
+                        ],
+                    }
+                }
+                None => array,
+            }
+        }
     }
 
-    fn encode_date(&mut self, unixtime: time::Duration) -> EncodeResult<()> {
-        let ms = unixtime.as_secs() * 1000 + (unixtime.subsec_nanos() as u64) / 1000_000;
-        try!(self.writer.write_u8(Marker::DATE));
-        try!(self.writer.write_f64::<BigEndian>(ms as f64));
-        try!(self.writer.write_i16::<BigEndian>(0));
-        Ok(())
+    impl ser::SerializeSeq for SeqBuilder {
+        type Ok = Value;
+        type Error = Error;
+        fn serialize_element<T: ?Sized>(&mut self, value: &T) -> Result<(), Error>
+        where
+            T: Serialize,
+        {
+            self.values.push(try!(value.serialize(ValueToValueSerializer)));
+            Ok(())
+        }
+        fn end(self) -> Result<Value, Error> {
+            Ok(self.into_value())
+        }
     }
 
-    // TODO: reference tableのサポート
-    fn encode_object(
-        &mut self,
-        name: &Option<String>,
-        pairs: &[Pair<String, Value>],
-    ) -> EncodeResult<()> {
-        if let Some(name) = name.as_ref() {
-            try!(self.writer.write_u8(Marker::TYPED_OBJECT));
-            try!(self.write_string(name));
-        } else {
-            try!(self.writer.write_u8(Marker::OBJECT));
+    impl ser::SerializeTuple for SeqBuilder {
+        type Ok = Value;
+        type Error = Error;
+        fn serialize_element<T: ?Sized>(&mut self, value: &T) -> Result<(), Error>
+        where
+            T: Serialize,
+        {
+            ser::SerializeSeq::serialize_element(self, value)
+        }
+        fn end(self) -> Result<Value, Error> {
+            ser::SerializeSeq::end(self)
         }
-        try!(self.encode_pairs(pairs));
-        Ok(())
     }
 
-    // TODO: reference tableのサポート
-    fn encode_ecma_array(&mut self, pairs: &[Pair<String, Value>]) -> EncodeResult<()> {
-        try!(self.writer.write_u8(Marker::ECMA_ARRAY));
-        try!(self.writer.write_u32::<BigEndian>(pairs.len() as u32)); // associative-count => u32
-        try!(self.encode_pairs(pairs));
-        Ok(())
+    impl ser::SerializeTupleStruct for SeqBuilder {
+        type Ok = Value;
+        type Error = Error;
+        fn serialize_field<T: ?Sized>(&mut self, value: &T) -> Result<(), Error>
+        where
+            T: Serialize,
+        {
+            ser::SerializeSeq::serialize_element(self, value)
+        }
+        fn end(self) -> Result<Value, Error> {
+            ser::SerializeSeq::end(self)
+        }
     }
 
-    // TODO: reference tableのサポート
-    fn encode_strict_array(&mut self, values: &[Value]) -> EncodeResult<()> {
-        try!(self.writer.write_u8(Marker::STRICT_ARRAY));
-        try!(self.writer.write_u32::<BigEndian>(values.len() as u32)); // array-count => u32
-        for v in values {
-            try!(self.encode_value(v));
+    impl ser::SerializeTupleVariant for SeqBuilder {
+        type Ok = Value;
+        type Error = Error;
+        fn serialize_field<T: ?Sized>(&mut self, value: &T) -> Result<(), Error>
+        where
+            T: Serialize,
+        {
+            ser::SerializeSeq::serialize_element(self, value)
+        }
+        fn end(self) -> Result<Value, Error> {
+            ser::SerializeSeq::end(self)
         }
-        Ok(())
     }
 
-    fn encode_avmplus(&mut self, value: &amf3::Value) -> EncodeResult<()> {
-        try!(self.writer.write_u8(Marker::AVMPLUS));
-        try!(amf3::Encoder::new(&mut self.writer).encode(value));
-        Ok(())
+    struct MapBuilder {
+        pairs: Vec<Pair<String, Value>>,
+        next_key: Option<String>,
+        variant: Option<&'static str>,
+    }
+
+    impl MapBuilder {
+        fn into_value(self) -> Value {
+            let map = Value::EcmaArray { pairs: self.pairs };
+            match self.variant {
+                Some(variant) => {
+                    Value::Object {
+                        name: None,
+                        pairs: vec![
+                            Pair {
+                                key: variant.to_string(),
+                                value: map,
+                            },
+                        ],
+                    }
+                }
+                None => map,
+            }
+        }
     }
 
-    fn encode_null(&mut self) -> EncodeResult<()> {
-        try!(self.writer.write_u8(Marker::NULL));
-        Ok(())
+    impl ser::SerializeMap for MapBuilder {
+        type Ok = Value;
+        type Error = Error;
+        fn serialize_key<T: ?Sized>(&mut self, key: &T) -> Result<(), Error>
+        where
+            T: Serialize,
+        {
+            let key = try!(key.serialize(ValueToValueSerializer));
+            self.next_key = Some(match key {
+                Value::String(s) | Value::LongString(s) => s,
+                other => return Err(Error(format!("non-string map key: {:?}", other))),
+            });
+            Ok(())
+        }
+        fn serialize_value<T: ?Sized>(&mut self, value: &T) -> Result<(), Error>
+        where
+            T: Serialize,
+        {
+            let key = self.next_key.take().unwrap_or_default();
+            self.pairs.push(Pair {
+                key: key,
+                value: try!(value.serialize(ValueToValueSerializer)),
+            });
+            Ok(())
+        }
+        fn end(self) -> Result<Value, Error> {
+            Ok(self.into_value())
+        }
     }
 
-    fn encode_undefined(&mut self) -> EncodeResult<()> {
-        try!(self.writer.write_u8(Marker::UNDEFINED));
-        Ok(())
+    impl ser::SerializeStruct for MapBuilder {
+        type Ok = Value;
+        type Error = Error;
+        fn serialize_field<T: ?Sized>(
+            &mut self,
+            key: &'static str,
+            value: &T,
+        ) -> Result<(), Error>
+        where
+            T: Serialize,
+        {
+            self.pairs.push(Pair {
+                key: key.to_string(),
+                value: try!(value.serialize(ValueToValueSerializer)),
+            });
+            Ok(())
+        }
+        fn end(self) -> Result<Value, Error> {
+            Ok(self.into_value())
+        }
     }
 
-    fn encode_value(&mut self, value: &Value) -> EncodeResult<()> {
-        match *value {
-            Value::Number(number) => self.encode_number(number),
-            Value::Boolean(boolean) => self.encode_boolean(boolean),
-            Value::String(ref string) => self.encode_string(string),
-            Value::Object {
-                ref name,
-                ref pairs,
-            } => self.encode_object(name, pairs),
-            Value::EcmaArray { ref pairs } => self.encode_ecma_array(pairs),
-            Value::Array { ref values } => self.encode_strict_array(values),
-            Value::Date { unixtime } => self.encode_date(unixtime),
-            Value::LongString(ref string) => self.encode_string(string),
-            Value::XmlDoc(ref xml_doc) => self.encode_xml_doc(xml_doc),
-            Value::AvmPlus(ref value) => self.encode_avmplus(value),
-            Value::Null => self.encode_null(),
-            Value::Undefined => self.encode_undefined(),
+    impl ser::SerializeStructVariant for MapBuilder {
+        type Ok = Value;
+        type Error = Error;
+        fn serialize_field<T: ?Sized>(
+            &mut self,
+            key: &'static str,
+            value: &T,
+        ) -> Result<(), Error>
+        where
+            T: Serialize,
+        {
+            ser::SerializeStruct::serialize_field(self, key, value)
+        }
+        fn end(self) -> Result<Value, Error> {
+            ser::SerializeStruct::end(self)
         }
     }
 }
@@ -377,15 +2157,20 @@ where
 mod test {
     use std::fs;
     use std::io::BufReader;
+    use std::error::Error;
     use std::f64;
     use std::time;
 
     use super::Value;
     use super::Pair;
     use super::DecodeError;
+    use super::DecodeErrorKind;
+    use super::EncodeError;
+    use super::EncodeErrorKind;
     use super::Decoder;
     use super::amf3;
     use super::Encoder;
+    use super::Marker;
 
     macro_rules! macro_decode {
         ($sample_file: expr) => {
@@ -475,7 +2260,10 @@ mod test {
     fn decode_date() {
         macro_decode_equal!(
             "amf0-date.bin",
-            Value::Date { unixtime: time::Duration::from_millis(1111111111_000) }
+            Value::Date {
+                unixtime: time::Duration::from_millis(1111111111_000),
+                time_zone: 0,
+            }
         );
     }
 
@@ -603,7 +2391,7 @@ mod test {
     fn decode_unsupported() {
         assert_eq!(
             macro_decode!("amf0-unsupported.bin"),
-            Err(DecodeError::NotSupportedType { marker: 13 })
+            Err(DecodeErrorKind::NotSupportedType { marker: 13 }.into())
         );
     }
 
@@ -611,7 +2399,7 @@ mod test {
     fn decode_recordset() {
         assert_eq!(
             macro_decode!("amf0-recordset.bin"),
-            Err(DecodeError::NotSupportedType { marker: 14 })
+            Err(DecodeErrorKind::NotSupportedType { marker: 14 }.into())
         );
     }
 
@@ -619,7 +2407,7 @@ mod test {
     fn decode_movieclip() {
         assert_eq!(
             macro_decode!("amf0-movieclip.bin"),
-            Err(DecodeError::NotSupportedType { marker: 4 })
+            Err(DecodeErrorKind::NotSupportedType { marker: 4 }.into())
         );
     }
 
@@ -646,7 +2434,7 @@ mod test {
     fn decode_object_end() {
         assert_eq!(
             macro_decode!("amf0-object-end.bin"),
-            Err(DecodeError::NotExpectedObjectEnd)
+            Err(DecodeErrorKind::NotExpectedObjectEnd.into())
         );
     }
 
@@ -694,7 +2482,10 @@ mod test {
     #[test]
     fn encode_date() {
         macro_encode_equal!(
-            Value::Date { unixtime: time::Duration::from_millis(1111111111_000) },
+            Value::Date {
+                unixtime: time::Duration::from_millis(1111111111_000),
+                time_zone: 0,
+            },
             "amf0-date.bin"
         );
     }
@@ -792,4 +2583,435 @@ mod test {
         });
         macro_encode_equal!(value, "amf0-avmplus-array.bin");
     }
+
+    #[test]
+    fn encode_with_references() {
+        let shared = Value::Object {
+            name: None,
+            pairs: vec![
+                Pair {
+                    key: "msg".to_string(),
+                    value: Value::String("Hello, world!".to_string()),
+                },
+            ],
+        };
+        let value = Value::Array {
+            values: vec![shared.clone(), shared.clone()],
+        };
+
+        let mut buf = Vec::new();
+        Encoder::new(&mut buf).with_references().encode(&value).unwrap();
+
+        let mut decoder = Decoder::new(&buf[..]);
+        let result = decoder.decode().unwrap();
+        assert_eq!(value, result);
+
+        // Without references enabled, the same value round-trips but produces
+        // a larger, fully-inlined encoding.
+        let mut buf_inline = Vec::new();
+        Encoder::new(&mut buf_inline).encode(&value).unwrap();
+        assert!(buf_inline.len() > buf.len());
+    }
+
+    #[test]
+    fn value_total_order() {
+        use std::collections::HashSet;
+
+        // -0.0 and +0.0 are distinct under the total order, unlike `==` on f64.
+        assert_ne!(Value::Number(-0.0_f64), Value::Number(0.0_f64));
+
+        // NaN becomes comparable and hashable.
+        let nan = Value::Number(f64::NAN);
+        assert_eq!(nan, nan.clone());
+
+        let mut set = HashSet::new();
+        set.insert(Value::String("a".to_string()));
+        set.insert(Value::String("a".to_string()));
+        set.insert(Value::Number(1.0));
+        assert_eq!(set.len(), 2);
+
+        let mut values = vec![
+            Value::Number(f64::INFINITY),
+            Value::Number(-0.0),
+            Value::Number(0.0),
+            Value::Number(f64::NEG_INFINITY),
+        ];
+        values.sort();
+        assert_eq!(
+            values,
+            vec![
+                Value::Number(f64::NEG_INFINITY),
+                Value::Number(-0.0),
+                Value::Number(0.0),
+                Value::Number(f64::INFINITY),
+            ]
+        );
+    }
+
+    #[test]
+    fn decode_all_concatenated_values() {
+        let mut buf = Vec::new();
+        Encoder::new(&mut buf).encode(&Value::Number(1.1)).unwrap();
+        Encoder::new(&mut buf)
+            .encode(&Value::String("hi".to_string()))
+            .unwrap();
+
+        let mut decoder = Decoder::new(&buf[..]);
+        let values = decoder.decode_all().unwrap();
+        assert_eq!(
+            values,
+            vec![Value::Number(1.1), Value::String("hi".to_string())]
+        );
+    }
+
+    #[test]
+    fn decode_all_rejects_truncated_trailing_value() {
+        let mut buf = Vec::new();
+        Encoder::new(&mut buf).encode(&Value::Number(1.1)).unwrap();
+        buf.push(Marker::NUMBER); // dangling marker with no payload
+
+        let mut decoder = Decoder::new(&buf[..]);
+        assert!(decoder.decode_all().is_err());
+    }
+
+    #[test]
+    fn into_inner_returns_the_underlying_reader_and_writer() {
+        let mut buf = Vec::new();
+        {
+            let mut encoder = Encoder::new(&mut buf);
+            encoder.encode(&Value::Null).unwrap();
+            let _: &mut Vec<u8> = encoder.into_inner();
+        }
+
+        let mut decoder = Decoder::new(&buf[..]);
+        decoder.decode().unwrap();
+        let remaining: &[u8] = decoder.into_inner();
+        assert!(remaining.is_empty());
+    }
+
+    #[test]
+    fn date_round_trips_with_non_zero_time_zone() {
+        let value = Value::Date {
+            unixtime: time::Duration::from_millis(1111111111_000),
+            time_zone: -480,
+        };
+
+        let mut buf = Vec::new();
+        Encoder::new(&mut buf).encode(&value).unwrap();
+
+        let mut decoder = Decoder::new(&buf[..]);
+        assert_eq!(decoder.decode().unwrap(), value);
+    }
+
+    #[test]
+    fn encode_rejects_oversized_object_key_instead_of_panicking() {
+        let long_key = "k".to_string().repeat(0x10000);
+        let value = Value::Object {
+            name: None,
+            pairs: vec![
+                Pair {
+                    key: long_key.clone(),
+                    value: Value::Null,
+                },
+            ],
+        };
+
+        let mut buf = Vec::new();
+        let err = Encoder::new(&mut buf).encode(&value).unwrap_err();
+        assert_eq!(err.kind, EncodeErrorKind::StringTooLong { len: long_key.len() });
+    }
+
+    #[test]
+    fn encode_auto_promotes_oversized_string_to_long_string() {
+        let long_string = "a".to_string().repeat(0x10000);
+        let value = Value::String(long_string.clone());
+
+        let mut buf = Vec::new();
+        Encoder::new(&mut buf).encode(&value).unwrap();
+
+        let mut decoder = Decoder::new(&buf[..]);
+        assert_eq!(decoder.decode().unwrap(), Value::LongString(long_string));
+    }
+
+    #[test]
+    fn decode_rejects_nesting_beyond_the_configured_max_depth() {
+        let value = Value::Array {
+            values: vec![Value::Array { values: vec![Value::Null] }],
+        };
+
+        let mut buf = Vec::new();
+        Encoder::new(&mut buf).encode(&value).unwrap();
+
+        let mut decoder = Decoder::new(&buf[..]).with_max_depth(1);
+        let err = decoder.decode().unwrap_err();
+        assert_eq!(err.kind, DecodeErrorKind::DepthLimitExceeded { limit: 1 });
+        assert_eq!(err.path.as_ref().map(String::as_str), Some("$[0]"));
+
+        // Without the guard the same bytes decode fine.
+        let mut unguarded = Decoder::new(&buf[..]);
+        assert_eq!(unguarded.decode().unwrap(), value);
+    }
+
+    #[test]
+    fn decode_rejects_collection_counts_beyond_the_configured_max_len() {
+        let mut buf = Vec::new();
+        buf.push(Marker::STRICT_ARRAY);
+        buf.extend_from_slice(&[0xFF, 0xFF, 0xFF, 0xFF]); // forged element count
+
+        let mut decoder = Decoder::new(&buf[..]).with_max_collection_len(1000);
+        let err = decoder.decode().unwrap_err();
+        assert_eq!(err.kind, DecodeErrorKind::CollectionTooLarge { len: 0xFFFF_FFFF });
+        assert_eq!(err.path, None);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn value_serde_round_trip() {
+        use serde_derive::{Serialize, Deserialize};
+        use super::value_serde::{to_value, from_value};
+
+        #[derive(Serialize, Deserialize, PartialEq, Debug)]
+        struct Hoge {
+            index: f64,
+            msg: String,
+        }
+
+        let hoge = Hoge {
+            index: 0_f64,
+            msg: "fugaaaaaaa".to_string(),
+        };
+
+        let value = to_value(&hoge).unwrap();
+        assert_eq!(
+            value,
+            Value::EcmaArray {
+                pairs: vec![
+                    Pair {
+                        key: "index".to_string(),
+                        value: Value::Number(0_f64),
+                    },
+                    Pair {
+                        key: "msg".to_string(),
+                        value: Value::String("fugaaaaaaa".to_string()),
+                    },
+                ],
+            }
+        );
+
+        let round_tripped: Hoge = from_value(value).unwrap();
+        assert_eq!(round_tripped, hoge);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn value_serde_tags_struct_and_tuple_variants_by_name() {
+        use serde_derive::Serialize;
+        use super::value_serde::to_value;
+
+        #[derive(Serialize)]
+        enum Command {
+            Play { name: String },
+            Seek(f64, f64),
+        }
+
+        let value = to_value(&Command::Play { name: "a".to_string() }).unwrap();
+        assert_eq!(
+            value,
+            Value::Object {
+                name: None,
+                pairs: vec![
+                    Pair {
+                        key: "Play".to_string(),
+                        value: Value::EcmaArray {
+                            pairs: vec![
+                                Pair {
+                                    key: "name".to_string(),
+                                    value: Value::String("a".to_string()),
+                                },
+                            ],
+                        },
+                    },
+                ],
+            }
+        );
+
+        let value = to_value(&Command::Seek(1.5, 2.5)).unwrap();
+        assert_eq!(
+            value,
+            Value::Object {
+                name: None,
+                pairs: vec![
+                    Pair {
+                        key: "Seek".to_string(),
+                        value: Value::Array {
+                            values: vec![Value::Number(1.5), Value::Number(2.5)],
+                        },
+                    },
+                ],
+            }
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn value_serde_round_trips_struct_tuple_and_unit_variants() {
+        use serde_derive::{Serialize, Deserialize};
+        use super::value_serde::{to_value, from_value};
+
+        #[derive(Serialize, Deserialize, PartialEq, Debug)]
+        enum Command {
+            Play { name: String },
+            Seek(f64, f64),
+            Stop,
+        }
+
+        let play = Command::Play { name: "a".to_string() };
+        assert_eq!(from_value::<Command>(to_value(&play).unwrap()).unwrap(), play);
+
+        let seek = Command::Seek(1.5, 2.5);
+        assert_eq!(from_value::<Command>(to_value(&seek).unwrap()).unwrap(), seek);
+
+        let stop = Command::Stop;
+        assert_eq!(from_value::<Command>(to_value(&stop).unwrap()).unwrap(), stop);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn value_serde_to_writer_from_reader_round_trip() {
+        use serde_derive::{Serialize, Deserialize};
+        use super::value_serde::{to_writer, from_reader};
+
+        #[derive(Serialize, Deserialize, PartialEq, Debug)]
+        struct Hoge {
+            index: f64,
+            msg: String,
+        }
+
+        let hoge = Hoge {
+            index: 1_f64,
+            msg: "fuga".to_string(),
+        };
+
+        let mut buf = Vec::new();
+        to_writer(&mut buf, &hoge).unwrap();
+
+        let round_tripped: Hoge = from_reader(&buf[..]).unwrap();
+        assert_eq!(round_tripped, hoge);
+    }
+
+    #[test]
+    fn read_borrowed_strings_borrow_from_the_source_buffer() {
+        use std::borrow::Cow;
+        use super::{ValueRef, read_borrowed};
+
+        let mut buf = Vec::new();
+        let mut encoder = Encoder::new(&mut buf);
+        encoder
+            .encode(&Value::EcmaArray {
+                pairs: vec![
+                    Pair {
+                        key: "name".to_string(),
+                        value: Value::String("flashver".to_string()),
+                    },
+                ],
+            })
+            .unwrap();
+
+        let value = read_borrowed(&buf).unwrap();
+        match value {
+            ValueRef::EcmaArray { ref pairs } => {
+                assert_eq!(pairs.len(), 1);
+                assert_eq!(pairs[0].key, Cow::Borrowed("name"));
+                match pairs[0].value {
+                    ValueRef::String(ref s) => {
+                        assert_eq!(*s, Cow::Borrowed("flashver"));
+                        assert!(match *s {
+                            Cow::Borrowed(_) => true,
+                            Cow::Owned(_) => false,
+                        });
+                    }
+                    ref other => panic!("expected a borrowed string, got {:?}", other),
+                }
+            }
+            ref other => panic!("expected an EcmaArray, got {:?}", other),
+        }
+
+        assert_eq!(
+            value.to_owned(),
+            Value::EcmaArray {
+                pairs: vec![
+                    Pair {
+                        key: "name".to_string(),
+                        value: Value::String("flashver".to_string()),
+                    },
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn read_borrowed_is_unbounded_by_default_but_bounded_via_borrowed_decoder() {
+        use super::{BorrowedDecoder, read_borrowed};
+
+        let value = Value::Array {
+            values: vec![Value::Array { values: vec![Value::Null] }],
+        };
+        let mut buf = Vec::new();
+        Encoder::new(&mut buf).encode(&value).unwrap();
+
+        assert_eq!(read_borrowed(&buf).unwrap().to_owned(), value);
+
+        let err = BorrowedDecoder::new(&buf).with_max_depth(1).decode().unwrap_err();
+        assert_eq!(err.kind, DecodeErrorKind::DepthLimitExceeded { limit: 1 });
+    }
+
+    #[test]
+    fn read_borrowed_rejects_collection_counts_beyond_the_configured_max_len() {
+        use super::BorrowedDecoder;
+
+        // STRICT_ARRAY with a forged element count of 0xFFFFFFFF.
+        let buf: Vec<u8> = vec![Marker::STRICT_ARRAY, 0xFF, 0xFF, 0xFF, 0xFF];
+
+        let err = BorrowedDecoder::new(&buf).with_max_collection_len(1000).decode().unwrap_err();
+        assert_eq!(err.kind, DecodeErrorKind::CollectionTooLarge { len: 0xFFFF_FFFF });
+    }
+
+    #[test]
+    fn decode_error_reports_the_path_to_the_failure() {
+        // { "info": { "metadata": [null, null, <unsupported marker>] } }
+        let mut buf = Vec::new();
+        buf.push(Marker::OBJECT);
+        buf.extend_from_slice(&[0x00, 0x04]);
+        buf.extend_from_slice(b"info");
+        buf.push(Marker::OBJECT);
+        buf.extend_from_slice(&[0x00, 0x08]);
+        buf.extend_from_slice(b"metadata");
+        buf.push(Marker::STRICT_ARRAY);
+        buf.extend_from_slice(&[0x00, 0x00, 0x00, 0x03]); // 3 elements
+        buf.push(Marker::NULL);
+        buf.push(Marker::NULL);
+        buf.push(Marker::MOVIECLIP); // reserved/unsupported marker
+        buf.extend_from_slice(&[0x00, 0x00]); // empty key
+        buf.push(Marker::OBJECT_END); // closes "metadata"'s object
+        buf.extend_from_slice(&[0x00, 0x00]); // empty key
+        buf.push(Marker::OBJECT_END); // closes "info"'s object
+
+        let err = Decoder::new(&buf[..]).decode().unwrap_err();
+        assert_eq!(err.kind, DecodeErrorKind::NotSupportedType { marker: Marker::MOVIECLIP });
+        assert_eq!(err.path.as_ref().map(String::as_str), Some("$.info.metadata[2]"));
+        assert_eq!(err.offset, Some(26));
+        assert_eq!(
+            err.to_string(),
+            "Not supported type: marker=4 at $.info.metadata[2] (byte 26)"
+        );
+    }
+
+    #[test]
+    fn decode_error_reports_the_byte_offset_of_an_io_failure() {
+        // Truncated right after the OBJECT marker: the key-length u16 never arrives.
+        let buf = [Marker::OBJECT];
+        let err = Decoder::new(&buf[..]).decode().unwrap_err();
+        assert!(err.source().is_some());
+        assert_eq!(err.offset, Some(1));
+    }
 }
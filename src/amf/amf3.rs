@@ -1,12 +1,87 @@
-use std::{io, time};
+use std::{cmp, fmt, hash, io, str, time};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::rc::Rc;
 
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 
-use super::{Pair, DecodeResult, DecodeError, EncodeResult, EncodeError};
+use super::{Pair, DecodeResult, DecodeError, DecodeErrorKind, EncodeResult, EncodeError,
+            EncodeErrorKind, PathSegment, CountingReader, CountingWriter, float_order_key};
 
 pub const MAX_29B_INT: i32 = 0x0FFF_FFFF;
 pub const MIN_29B_INT: i32 = -0x1000_0000;
 
+// 1.3.1 Variable Length Unsigned 29-bit Integer Encoding
+// AMF 3 makes use of a special compact format for writing integers to reduce the number
+// of bytes required for encoding. As with a normal 32-bit integer, up to 4 bytes are required
+// to hold the value however the high bit of the first 3 bytes are used as flags to determine
+// whether the next byte is part of the integer. With up to 3 bits of the 32 bits being used as
+// flags, only 29 significant bits remain for encoding an integer. This means the largest
+// unsigned integer value that can be represented is 229 - 1.
+// (hex) : (binary)
+// 0x00000000 - 0x0000007F : 0xxxxxxx
+// 0x00000080 - 0x00003FFF : 1xxxxxxx 0xxxxxxx
+// 0x00004000 - 0x001FFFFF : 1xxxxxxx 1xxxxxxx 0xxxxxxx
+// 0x00200000 - 0x3FFFFFFF : 1xxxxxxx 1xxxxxxx 1xxxxxxx xxxxxxxx
+// 0x40000000 - 0xFFFFFFFF : throw range exception
+// In ABNF syntax, the variable length unsigned 29-bit integer type is described as follows:
+// U29 = U29-1 | U29-2 | U29-3 | U29-4
+// U29-1 = %x00-7F
+// U29-2 = %x80-FF %x00-7F
+// U29-3 = %x80-FF %x80-FF %x00-7F
+// U29-4 = %x80-FF %x80-FF %x80-FF %x00-FF
+//
+// Shared by `Decoder`, `Encoder` and `BorrowedCursor`, since the reference
+// tables for strings/objects/traits also index through a U29.
+fn read_u29<R: io::Read>(reader: &mut R) -> DecodeResult<u32> {
+    let mut n = 0;
+    for _ in 0..3 {
+        let b = try!(reader.read_u8()) as u32;
+        n = (n << 7) | (b & 0x7f);
+        if (b & 0x80) == 0 {
+            return Ok(n);
+        }
+    }
+    let b = try!(reader.read_u8()) as u32;
+    n = (n << 8) | b;
+    Ok(n)
+}
+
+fn write_u29<W: io::Write>(writer: &mut W, u29: u32) -> EncodeResult<()> {
+    if u29 < 0x80 {
+        // U29-1
+        try!(writer.write_u8(u29 as u8));
+    } else if u29 < 0x4000 {
+        // U29-2
+        let b1 = (u29 >> 7 | 0x80) as u8;
+        let b2 = (u29 & 0x7F) as u8;
+        for b in &[b1, b2] {
+            try!(writer.write_u8(*b));
+        }
+    } else if u29 > 0x3FFF && u29 <= 0x1FFFFF {
+        // U29-3
+        let b1 = (u29 >> 14 | 0x80) as u8;
+        let b2 = (((u29 >> 7) & 0x7F) | 0x80) as u8;
+        let b3 = (u29 & 0x7F) as u8;
+        for b in &[b1, b2, b3] {
+            try!(writer.write_u8(*b));
+        }
+    } else if u29 < 0x4000_0000 {
+        // U29-4
+        let b1 = (u29 >> 22 | 0x80) as u8;
+        let b2 = (((u29 >> 15) & 0x7F) | 0x80) as u8;
+        let b3 = (((u29 >> 8) & 0x7F) | 0x80) as u8;
+        let b4 = (u29 & 0xFF) as u8;
+        for b in &[b1, b2, b3, b4] {
+            try!(writer.write_u8(*b));
+        }
+    } else {
+        return Err(EncodeErrorKind::U29Overflow { u29 }.into());
+    }
+    Ok(())
+}
+
 #[allow(non_snake_case)]
 pub mod Marker {
     pub const UNDEFINED: u8 = 0x00;
@@ -29,7 +104,7 @@ pub mod Marker {
     pub const DICTIONARY: u8 = 0x11;
 }
 
-#[derive(Debug, Clone, PartialEq, PartialOrd)]
+#[derive(Debug, Clone)]
 pub enum Value {
     Undefined,
     Null,
@@ -64,19 +139,264 @@ pub enum Value {
     },
 }
 
-#[derive(Debug, Clone)]
+impl Value {
+    // Stable discriminant rank, used to order/hash values of different variants.
+    fn rank(&self) -> u8 {
+        match *self {
+            Value::Undefined => 0,
+            Value::Null => 1,
+            Value::Boolean(_) => 2,
+            Value::Integer(_) => 3,
+            Value::Double(_) => 4,
+            Value::String(_) => 5,
+            Value::XmlDoc(_) => 6,
+            Value::Date { .. } => 7,
+            Value::Object { .. } => 8,
+            Value::Xml(_) => 9,
+            Value::Array { .. } => 10,
+            Value::ByteArray(_) => 11,
+            Value::IntVector { .. } => 12,
+            Value::UintVector { .. } => 13,
+            Value::DoubleVector { .. } => 14,
+            Value::ObjectVector { .. } => 15,
+            Value::Dictionary { .. } => 16,
+        }
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == cmp::Ordering::Equal
+    }
+}
+
+impl Eq for Value {}
+
+impl PartialOrd for Value {
+    fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Value {
+    fn cmp(&self, other: &Self) -> cmp::Ordering {
+        match (self, other) {
+            (&Value::Undefined, &Value::Undefined) => cmp::Ordering::Equal,
+            (&Value::Null, &Value::Null) => cmp::Ordering::Equal,
+            (&Value::Boolean(a), &Value::Boolean(b)) => a.cmp(&b),
+            (&Value::Integer(a), &Value::Integer(b)) => a.cmp(&b),
+            (&Value::Double(a), &Value::Double(b)) => {
+                float_order_key(a).cmp(&float_order_key(b))
+            }
+            (&Value::String(ref a), &Value::String(ref b)) => a.cmp(b),
+            (&Value::XmlDoc(ref a), &Value::XmlDoc(ref b)) => a.cmp(b),
+            (&Value::Date { unixtime: a }, &Value::Date { unixtime: b }) => a.cmp(&b),
+            (&Value::Object {
+                 name: ref an,
+                 sealed_count: asc,
+                 pairs: ref ap,
+             },
+             &Value::Object {
+                 name: ref bn,
+                 sealed_count: bsc,
+                 pairs: ref bp,
+             }) => an.cmp(bn).then_with(|| asc.cmp(&bsc)).then_with(
+                || ap.cmp(bp),
+            ),
+            (&Value::Xml(ref a), &Value::Xml(ref b)) => a.cmp(b),
+            (&Value::Array {
+                 assoc_entries: ref aa,
+                 dense_entries: ref ad,
+             },
+             &Value::Array {
+                 assoc_entries: ref ba,
+                 dense_entries: ref bd,
+             }) => aa.cmp(ba).then_with(|| ad.cmp(bd)),
+            (&Value::ByteArray(ref a), &Value::ByteArray(ref b)) => a.cmp(b),
+            (&Value::IntVector {
+                 is_fixed: af,
+                 entries: ref ae,
+             },
+             &Value::IntVector {
+                 is_fixed: bf,
+                 entries: ref be,
+             }) => af.cmp(&bf).then_with(|| ae.cmp(be)),
+            (&Value::UintVector {
+                 is_fixed: af,
+                 entries: ref ae,
+             },
+             &Value::UintVector {
+                 is_fixed: bf,
+                 entries: ref be,
+             }) => af.cmp(&bf).then_with(|| ae.cmp(be)),
+            (&Value::DoubleVector {
+                 is_fixed: af,
+                 entries: ref ae,
+             },
+             &Value::DoubleVector {
+                 is_fixed: bf,
+                 entries: ref be,
+             }) => {
+                af.cmp(&bf).then_with(|| {
+                    ae.iter()
+                        .map(|&v| float_order_key(v))
+                        .cmp(be.iter().map(|&v| float_order_key(v)))
+                })
+            }
+            (&Value::ObjectVector {
+                 name: ref an,
+                 is_fixed: af,
+                 entries: ref ae,
+             },
+             &Value::ObjectVector {
+                 name: ref bn,
+                 is_fixed: bf,
+                 entries: ref be,
+             }) => an.cmp(bn).then_with(|| af.cmp(&bf)).then_with(
+                || ae.cmp(be),
+            ),
+            (&Value::Dictionary {
+                 is_weak: aw,
+                 entries: ref ae,
+             },
+             &Value::Dictionary {
+                 is_weak: bw,
+                 entries: ref be,
+             }) => aw.cmp(&bw).then_with(|| ae.cmp(be)),
+            (a, b) => a.rank().cmp(&b.rank()),
+        }
+    }
+}
+
+impl hash::Hash for Value {
+    fn hash<H: hash::Hasher>(&self, state: &mut H) {
+        self.rank().hash(state);
+        match *self {
+            Value::Undefined | Value::Null => {}
+            Value::Boolean(b) => b.hash(state),
+            Value::Integer(n) => n.hash(state),
+            Value::Double(n) => float_order_key(n).hash(state),
+            Value::String(ref s) => s.hash(state),
+            Value::XmlDoc(ref s) => s.hash(state),
+            Value::Date { unixtime } => unixtime.hash(state),
+            Value::Object {
+                ref name,
+                sealed_count,
+                ref pairs,
+            } => {
+                name.hash(state);
+                sealed_count.hash(state);
+                pairs.hash(state);
+            }
+            Value::Xml(ref s) => s.hash(state),
+            Value::Array {
+                ref assoc_entries,
+                ref dense_entries,
+            } => {
+                assoc_entries.hash(state);
+                dense_entries.hash(state);
+            }
+            Value::ByteArray(ref b) => b.hash(state),
+            Value::IntVector {
+                is_fixed,
+                ref entries,
+            } => {
+                is_fixed.hash(state);
+                entries.hash(state);
+            }
+            Value::UintVector {
+                is_fixed,
+                ref entries,
+            } => {
+                is_fixed.hash(state);
+                entries.hash(state);
+            }
+            Value::DoubleVector {
+                is_fixed,
+                ref entries,
+            } => {
+                is_fixed.hash(state);
+                for &v in entries {
+                    float_order_key(v).hash(state);
+                }
+            }
+            Value::ObjectVector {
+                ref name,
+                is_fixed,
+                ref entries,
+            } => {
+                name.hash(state);
+                is_fixed.hash(state);
+                entries.hash(state);
+            }
+            Value::Dictionary {
+                is_weak,
+                ref entries,
+            } => {
+                is_weak.hash(state);
+                entries.hash(state);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 struct Class {
     name: Option<String>,
     is_dynamic: bool,
+    is_externalizable: bool,
     fields: Vec<String>,
 }
 
-#[derive(Debug)]
+/// Decodes the payload of one Flex messaging `IExternalizable` class, whose
+/// wire format AMF3 deliberately leaves opaque to the generic codec. Register
+/// an implementation for a given class alias with
+/// `Decoder::register_externalizable` to decode that alias instead of
+/// failing with `DecodeErrorKind::ExternalizableType`.
+pub trait Externalizable {
+    fn read_external<R: io::Read>(decoder: &mut Decoder<R>) -> DecodeResult<Value>;
+}
+
+// The common Flex collection wrappers (`ArrayCollection`, `ObjectProxy`, ...)
+// are `IExternalizable` only in name: their `writeExternal` just writes one
+// ordinary AMF3 value (the wrapped array/object) and `readExternal` reads it
+// straight back. Registering them by default means real Flex Remoting
+// payloads decode out of the box without the caller having to know this.
+const BUILTIN_FLEX_WRAPPERS: &'static [&'static str] = &[
+    "flex.messaging.io.ArrayCollection",
+    "flex.messaging.io.ObjectProxy",
+];
+
 pub struct Decoder<R> {
-    reader: R,
+    reader: CountingReader<R>,
     objects: Vec<Value>,
     strings: Vec<String>,
     classes: Vec<Class>,
+    max_depth: usize,
+    max_collection_len: usize,
+    max_string_len: usize,
+    depth: usize,
+    path: Vec<PathSegment>,
+    externalizables: HashMap<String, Rc<Fn(&mut Decoder<R>) -> DecodeResult<Value>>>,
+}
+
+// Registered externalizable handlers aren't `Debug`, so this can't be
+// `#[derive(Debug)]`; list the registry by class name instead of skipping it.
+impl<R: fmt::Debug> fmt::Debug for Decoder<R> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Decoder")
+            .field("reader", &self.reader)
+            .field("objects", &self.objects)
+            .field("strings", &self.strings)
+            .field("classes", &self.classes)
+            .field("max_depth", &self.max_depth)
+            .field("max_collection_len", &self.max_collection_len)
+            .field("max_string_len", &self.max_string_len)
+            .field("depth", &self.depth)
+            .field("path", &self.path)
+            .field("externalizables", &self.externalizables.keys().collect::<Vec<_>>())
+            .finish()
+    }
 }
 
 impl<R> Decoder<R>
@@ -84,52 +404,139 @@ where
     R: io::Read,
 {
     pub fn new(reader: R) -> Self {
-        Decoder {
-            reader: reader,
+        let mut decoder = Decoder {
+            reader: CountingReader::new(reader),
             objects: Vec::new(),
             strings: Vec::new(),
             classes: Vec::new(),
+            max_depth: usize::max_value(),
+            max_collection_len: usize::max_value(),
+            max_string_len: usize::max_value(),
+            depth: 0,
+            path: Vec::new(),
+            externalizables: HashMap::new(),
+        };
+        for class_name in BUILTIN_FLEX_WRAPPERS {
+            decoder.register_external(class_name, |d: &mut Decoder<R>| d.decode_next());
+        }
+        decoder
+    }
+
+    /// Registers `f` as the handler for the `IExternalizable` class named
+    /// `class_name`, so `decode` calls `f` with the `Decoder` positioned just
+    /// after the trait header, instead of failing with
+    /// `DecodeErrorKind::ExternalizableType`, when that class is encountered.
+    /// Overrides any handler previously registered for the same name,
+    /// including the built-in Flex collection wrappers.
+    pub fn register_external<F>(&mut self, class_name: &str, f: F)
+    where
+        F: Fn(&mut Decoder<R>) -> DecodeResult<Value> + 'static,
+    {
+        self.externalizables.insert(class_name.to_string(), Rc::new(f));
+    }
+
+    /// Registers `T` as the handler for the `IExternalizable` class aliased
+    /// `alias` (e.g. `"flex.messaging.io.ArrayCollection"`), so `decode`
+    /// calls `T::read_external` instead of failing with
+    /// `DecodeErrorKind::ExternalizableType` when that alias is encountered.
+    pub fn register_externalizable<T: Externalizable>(mut self, alias: &str) -> Self {
+        self.register_external(alias, |d| T::read_external(d));
+        self
+    }
+
+    /// Bounds how deeply objects/arrays/vectors/dictionaries may nest while
+    /// decoding, guarding against stack exhaustion from hostile input.
+    /// Unlimited by default.
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// Bounds the element/associative-pair/trait-field count a single
+    /// object, array, byte array, vector, or dictionary may declare,
+    /// guarding against memory exhaustion from a forged length. Unlimited by
+    /// default.
+    pub fn with_max_collection_len(mut self, max_collection_len: usize) -> Self {
+        self.max_collection_len = max_collection_len;
+        self
+    }
+
+    /// Bounds the byte length a single inline string (including object
+    /// keys, class names, and XML payloads) may declare, guarding against
+    /// memory exhaustion from a forged length. Byte arrays are bounded by
+    /// `with_max_collection_len` instead. Unlimited by default.
+    pub fn with_max_string_len(mut self, max_string_len: usize) -> Self {
+        self.max_string_len = max_string_len;
+        self
+    }
+
+    fn enter_nested(&mut self) -> DecodeResult<()> {
+        self.depth += 1;
+        if self.depth > self.max_depth {
+            return Err(DecodeErrorKind::DepthLimitExceeded { limit: self.max_depth }.into());
+        }
+        Ok(())
+    }
+
+    fn exit_nested(&mut self) {
+        self.depth -= 1;
+    }
+
+    fn check_collection_len(&self, len: usize) -> DecodeResult<()> {
+        if len > self.max_collection_len {
+            return Err(DecodeErrorKind::CollectionTooLarge { len: len }.into());
+        }
+        Ok(())
+    }
+
+    fn check_string_len(&self, len: usize) -> DecodeResult<()> {
+        if len > self.max_string_len {
+            return Err(DecodeErrorKind::StringTooLong { len: len }.into());
         }
+        Ok(())
+    }
+
+    /// The number of bytes consumed from the underlying reader so far,
+    /// i.e. the absolute offset an error occurring right now would carry.
+    pub fn offset(&self) -> u64 {
+        self.reader.offset()
     }
 
     pub fn decode(&mut self) -> DecodeResult<Value> {
         self.objects.clear();
         self.strings.clear();
         self.classes.clear();
-        self.decode_value()
-    }
-
-    // 1.3.1 Variable Length Unsigned 29-bit Integer Encoding
-    // AMF 3 makes use of a special compact format for writing integers to reduce the number
-    // of bytes required for encoding. As with a normal 32-bit integer, up to 4 bytes are required
-    // to hold the value however the high bit of the first 3 bytes are used as flags to determine
-    // whether the next byte is part of the integer. With up to 3 bits of the 32 bits being used as
-    // flags, only 29 significant bits remain for encoding an integer. This means the largest
-    // unsigned integer value that can be represented is 229 - 1.
-    // (hex) : (binary)
-    // 0x00000000 - 0x0000007F : 0xxxxxxx
-    // 0x00000080 - 0x00003FFF : 1xxxxxxx 0xxxxxxx
-    // 0x00004000 - 0x001FFFFF : 1xxxxxxx 1xxxxxxx 0xxxxxxx
-    // 0x00200000 - 0x3FFFFFFF : 1xxxxxxx 1xxxxxxx 1xxxxxxx xxxxxxxx
-    // 0x40000000 - 0xFFFFFFFF : throw range exception
-    // In ABNF syntax, the variable length unsigned 29-bit integer type is described as follows:
-    // U29 = U29-1 | U29-2 | U29-3 | U29-4
-    // U29-1 = %x00-7F
-    // U29-2 = %x80-FF %x00-7F
-    // U29-3 = %x80-FF %x80-FF %x00-7F
-    // U29-4 = %x80-FF %x80-FF %x80-FF %x00-FF
+        self.path.clear();
+        self.decode_next()
+    }
+
+    /// Decodes the next value without resetting the object/string/trait
+    /// reference tables, so a reference can resolve against a value decoded
+    /// earlier in the same logical AMF3 stream. Also the right way for an
+    /// `Externalizable` handler to decode the value(s) nested inside its own
+    /// payload, since calling `decode` there would reset the tables the
+    /// enclosing decode is still relying on.
+    pub fn decode_next(&mut self) -> DecodeResult<Value> {
+        self.decode_value().map_err(|e| e.with_offset(self.reader.offset()))
+    }
+
+    /// Decodes values sharing one reference-table scope, one at a time,
+    /// stopping cleanly (no error) once the reader reaches end-of-stream
+    /// before any marker byte is read. Unlike collecting every value up
+    /// front, a truncated value in the middle of the stream only fails the
+    /// `Item` it belongs to; values already yielded to the caller are not
+    /// lost, which matters since RTMP command payloads commonly carry
+    /// several AMF3 values back-to-back.
+    pub fn values(&mut self) -> Values<'_, R> {
+        self.objects.clear();
+        self.strings.clear();
+        self.classes.clear();
+        self.path.clear();
+        Values { decoder: self }
+    }
+
     fn decode_u29(&mut self) -> DecodeResult<u32> {
-        let mut n = 0;
-        for _ in 0..3 {
-            let b = try!(self.reader.read_u8()) as u32;
-            n = (n << 7) | (b & 0x7f);
-            if (b & 0x80) == 0 {
-                return Ok(n);
-            }
-        }
-        let b = try!(self.reader.read_u8()) as u32;
-        n = (n << 8) | b;
-        Ok(n)
+        read_u29(&mut self.reader)
     }
 
     fn read_bytes(&mut self, len: usize) -> DecodeResult<Vec<u8>> {
@@ -146,10 +553,11 @@ where
             let index = u29 >> 1;
             self.strings
                 .get(index)
-                .ok_or(DecodeError::NotFoundInReferenceTable { index: index })
+                .ok_or(DecodeErrorKind::NotFoundInReferenceTable { index: index }.into())
                 .and_then(|v| Ok(v.clone()))
         } else {
             let size = u29 >> 1;
+            try!(self.check_string_len(size));
             let bytes = try!(self.read_bytes(size));
             let s = try!(String::from_utf8(bytes));
             if !s.is_empty() {
@@ -166,10 +574,12 @@ where
             if key.is_empty() {
                 return Ok(pairs);
             }
-            let value = try!(self.decode_value());
+            self.path.push(PathSegment::Key(key.clone()));
+            let value = self.decode_value().map_err(|e| e.with_path(&self.path));
+            self.path.pop();
             pairs.push(Pair {
                 key: key,
-                value: value,
+                value: try!(value),
             });
         }
     }
@@ -202,13 +612,27 @@ where
             let index = u29 >> 1;
             self.objects
                 .get(index)
-                .ok_or(DecodeError::NotFoundInReferenceTable { index: index })
+                .ok_or(DecodeErrorKind::NotFoundInReferenceTable { index: index }.into())
                 .and_then(|v| Ok(v.clone()))
         } else {
+            let index = self.objects.len();
+            self.objects.push(Value::Null);
+
             let size = u29 >> 1;
-            self.read_bytes(size)
-                .and_then(|b| Ok(try!(String::from_utf8(b))))
-                .map(Value::XmlDoc)
+            try!(self.check_string_len(size));
+            let value = Value::XmlDoc(try!(
+                self.read_bytes(size).and_then(|b| Ok(try!(String::from_utf8(b))))
+            ));
+
+            self.objects[index] = value.clone();
+            Ok(value)
+        }
+    }
+
+    fn decode_externalizable(&mut self, class_name: String) -> DecodeResult<Value> {
+        match self.externalizables.get(&class_name).cloned() {
+            Some(read_external) => read_external(self),
+            None => Err(DecodeErrorKind::ExternalizableType { name: class_name }.into()),
         }
     }
 
@@ -228,13 +652,20 @@ where
             let index = u29 >> 1;
             self.objects
                 .get(index)
-                .ok_or(DecodeError::NotFoundInReferenceTable { index: index })
+                .ok_or(DecodeErrorKind::NotFoundInReferenceTable { index: index }.into())
                 .and_then(|v| Ok(v.clone()))
         } else {
+            let index = self.objects.len();
+            self.objects.push(Value::Null);
+
             let size = u29 >> 1;
-            self.read_bytes(size)
-                .and_then(|b| Ok(try!(String::from_utf8(b))))
-                .map(Value::Xml)
+            try!(self.check_string_len(size));
+            let value = Value::Xml(try!(
+                self.read_bytes(size).and_then(|b| Ok(try!(String::from_utf8(b))))
+            ));
+
+            self.objects[index] = value.clone();
+            Ok(value)
         }
     }
 
@@ -246,43 +677,61 @@ where
             let index = u29 >> 1;
             self.objects
                 .get(index)
-                .ok_or(DecodeError::NotFoundInReferenceTable { index: index })
+                .ok_or(DecodeErrorKind::NotFoundInReferenceTable { index: index }.into())
                 .and_then(|v| Ok(v.clone()))
         } else {
+            let index = self.objects.len();
+            self.objects.push(Value::Null);
+
             let size = u29 >> 1;
-            if (size & 0x1) == 0 {
-                let index = size >> 0x1;
-                let klass = try!(self.classes.get(index).ok_or(
-                    DecodeError::NotFoundInReferenceTable { index: index },
+            let value = if (size & 0x1) == 0 {
+                let klass_index = size >> 0x1;
+                let klass = try!(self.classes.get(klass_index).ok_or(
+                    DecodeError::from(DecodeErrorKind::NotFoundInReferenceTable { index: klass_index }),
                 )).clone();
 
-                let mut pairs = try!(
-                    klass
-                        .fields
-                        .iter()
-                        .map(|k| {
-                            Ok(Pair {
+                if klass.is_externalizable {
+                    try!(self.decode_externalizable(klass.name.unwrap_or_default()))
+                } else {
+                    try!(self.enter_nested());
+                    let pairs: DecodeResult<_> = (|| {
+                        let mut pairs = Vec::with_capacity(klass.fields.len());
+                        for k in &klass.fields {
+                            self.path.push(PathSegment::Key(k.clone()));
+                            let value = self.decode_value().map_err(|e| e.with_path(&self.path));
+                            self.path.pop();
+                            pairs.push(Pair {
                                 key: k.clone(),
-                                value: try!(self.decode_value()),
-                            })
-                        })
-                        .collect::<DecodeResult<Vec<_>>>()
-                );
-
-                if klass.is_dynamic {
-                    pairs.extend(try!(self.decode_pairs()));
+                                value: try!(value),
+                            });
+                        }
+
+                        if klass.is_dynamic {
+                            pairs.extend(try!(self.decode_pairs()));
+                        }
+                        Ok(pairs)
+                    })();
+                    self.exit_nested();
+                    let pairs = try!(pairs);
+                    Value::Object {
+                        name: klass.name,
+                        sealed_count: pairs.len(),
+                        pairs: pairs,
+                    }
                 }
-                Ok(Value::Object {
-                    name: klass.name,
-                    sealed_count: pairs.len(),
-                    pairs: pairs,
-                })
             } else if (size & 0b10) != 0 {
                 let class_name = try!(self.decode_utf8());
-                Err(DecodeError::ExternalizableType { name: class_name })
+                self.classes.push(Class {
+                    name: Some(class_name.clone()),
+                    is_dynamic: false,
+                    is_externalizable: true,
+                    fields: Vec::new(),
+                });
+                try!(self.decode_externalizable(class_name))
             } else {
                 let is_dynamic = (size & 0b100) != 0;
                 let field_num = size >> 3;
+                try!(self.check_collection_len(field_num));
                 let class_name = try!(self.decode_utf8());
                 let fields = try!((0..field_num).map(|_| self.decode_utf8()).collect());
 
@@ -293,30 +742,38 @@ where
                         Some(class_name)
                     },
                     is_dynamic: is_dynamic,
+                    is_externalizable: false,
                     fields: fields,
                 };
                 self.classes.push(klass.clone());
-                let mut pairs = try!(
-                    klass
-                        .fields
-                        .iter()
-                        .map(|k| {
-                            Ok(Pair {
-                                key: k.clone(),
-                                value: try!(self.decode_value()),
-                            })
-                        })
-                        .collect::<DecodeResult<Vec<_>>>()
-                );
-                if klass.is_dynamic {
-                    pairs.extend(try!(self.decode_pairs()));
-                }
-                Ok(Value::Object {
+                try!(self.enter_nested());
+                let pairs: DecodeResult<_> = (|| {
+                    let mut pairs = Vec::with_capacity(klass.fields.len());
+                    for k in &klass.fields {
+                        self.path.push(PathSegment::Key(k.clone()));
+                        let value = self.decode_value().map_err(|e| e.with_path(&self.path));
+                        self.path.pop();
+                        pairs.push(Pair {
+                            key: k.clone(),
+                            value: try!(value),
+                        });
+                    }
+                    if klass.is_dynamic {
+                        pairs.extend(try!(self.decode_pairs()));
+                    }
+                    Ok(pairs)
+                })();
+                self.exit_nested();
+                let pairs = try!(pairs);
+                Value::Object {
                     name: klass.name,
                     sealed_count: pairs.len(),
                     pairs: pairs,
-                })
-            }
+                }
+            };
+
+            self.objects[index] = value.clone();
+            Ok(value)
         }
     }
 
@@ -328,15 +785,28 @@ where
             let index = u29 >> 1;
             self.objects
                 .get(index)
-                .ok_or(DecodeError::NotFoundInReferenceTable { index: index })
+                .ok_or(DecodeErrorKind::NotFoundInReferenceTable { index: index }.into())
                 .and_then(|v| Ok(v.clone()))
         } else {
             let index = self.objects.len();
             self.objects.push(Value::Null);
 
             let size = u29 >> 1;
-            let assoc = try!(self.decode_pairs());
-            let dense = try!((0..size).map(|_| self.decode_value()).collect());
+            try!(self.check_collection_len(size));
+            try!(self.enter_nested());
+            let entries: DecodeResult<_> = (|| {
+                let assoc = try!(self.decode_pairs());
+                let mut dense = Vec::with_capacity(size);
+                for i in 0..size {
+                    self.path.push(PathSegment::Index(i));
+                    let entry = self.decode_value().map_err(|e| e.with_path(&self.path));
+                    self.path.pop();
+                    dense.push(try!(entry));
+                }
+                Ok((assoc, dense))
+            })();
+            self.exit_nested();
+            let (assoc, dense) = try!(entries);
 
             let value = Value::Array {
                 assoc_entries: assoc,
@@ -356,13 +826,14 @@ where
             let index = u29 >> 1;
             self.objects
                 .get(index)
-                .ok_or(DecodeError::NotFoundInReferenceTable { index: index })
+                .ok_or(DecodeErrorKind::NotFoundInReferenceTable { index: index }.into())
                 .and_then(|v| Ok(v.clone()))
         } else {
             let index = self.objects.len();
             self.objects.push(Value::Null);
 
             let size = u29 >> 1;
+            try!(self.check_collection_len(size));
             let value = Value::ByteArray(try!(self.read_bytes(size)));
 
             self.objects[index] = value.clone();
@@ -378,13 +849,14 @@ where
             let index = u29 >> 1;
             self.objects
                 .get(index)
-                .ok_or(DecodeError::NotFoundInReferenceTable { index: index })
+                .ok_or(DecodeErrorKind::NotFoundInReferenceTable { index: index }.into())
                 .and_then(|v| Ok(v.clone()))
         } else {
             let index = self.objects.len();
             self.objects.push(Value::Null);
 
             let size = u29 >> 1;
+            try!(self.check_collection_len(size));
             let is_fixed = try!(self.reader.read_u8()) != 0;
             let entries = try!(
                 (0..size)
@@ -410,13 +882,14 @@ where
             let index = u29 >> 1;
             self.objects
                 .get(index)
-                .ok_or(DecodeError::NotFoundInReferenceTable { index: index })
+                .ok_or(DecodeErrorKind::NotFoundInReferenceTable { index: index }.into())
                 .and_then(|v| Ok(v.clone()))
         } else {
             let index = self.objects.len();
             self.objects.push(Value::Null);
 
             let size = u29 >> 1;
+            try!(self.check_collection_len(size));
             let is_fixed = try!(self.reader.read_u8()) != 0;
             let entries = try!(
                 (0..size)
@@ -442,13 +915,14 @@ where
             let index = u29 >> 1;
             self.objects
                 .get(index)
-                .ok_or(DecodeError::NotFoundInReferenceTable { index: index })
+                .ok_or(DecodeErrorKind::NotFoundInReferenceTable { index: index }.into())
                 .and_then(|v| Ok(v.clone()))
         } else {
             let index = self.objects.len();
             self.objects.push(Value::Null);
 
             let size = u29 >> 1;
+            try!(self.check_collection_len(size));
             let is_fixed = try!(self.reader.read_u8()) != 0;
             let entries = try!(
                 (0..size)
@@ -474,16 +948,20 @@ where
             let index = u29 >> 1;
             self.objects
                 .get(index)
-                .ok_or(DecodeError::NotFoundInReferenceTable { index: index })
+                .ok_or(DecodeErrorKind::NotFoundInReferenceTable { index: index }.into())
                 .and_then(|v| Ok(v.clone()))
         } else {
             let index = self.objects.len();
             self.objects.push(Value::Null);
 
             let size = u29 >> 1;
+            try!(self.check_collection_len(size));
             let is_fixed = try!(self.reader.read_u8()) != 0;
             let name = try!(self.decode_utf8());
-            let entries = try!((0..size).map(|_| self.decode_value()).collect());
+            try!(self.enter_nested());
+            let entries = (0..size).map(|_| self.decode_value()).collect();
+            self.exit_nested();
+            let entries = try!(entries);
 
             let value = Value::ObjectVector {
                 name: if name == "*" { None } else { Some(name) },
@@ -504,24 +982,34 @@ where
             let index = u29 >> 1;
             self.objects
                 .get(index)
-                .ok_or(DecodeError::NotFoundInReferenceTable { index: index })
+                .ok_or(DecodeErrorKind::NotFoundInReferenceTable { index: index }.into())
                 .and_then(|v| Ok(v.clone()))
         } else {
             let index = self.objects.len();
             self.objects.push(Value::Null);
 
             let size = u29 >> 1;
+            try!(self.check_collection_len(size));
             let is_weak = try!(self.reader.read_u8()) == 1;
-            let entries = try!(
-                (0..size)
-                    .map(|_| {
+            try!(self.enter_nested());
+            let entries: DecodeResult<_> = (|| {
+                let mut entries = Vec::with_capacity(size);
+                for i in 0..size {
+                    self.path.push(PathSegment::Index(i));
+                    let pair = (|| {
                         Ok(Pair {
                             key: try!(self.decode_value()),
                             value: try!(self.decode_value()),
                         })
-                    })
-                    .collect::<DecodeResult<_>>()
-            );
+                    })()
+                        .map_err(|e: DecodeError| e.with_path(&self.path));
+                    self.path.pop();
+                    entries.push(try!(pair));
+                }
+                Ok(entries)
+            })();
+            self.exit_nested();
+            let entries = try!(entries);
 
             let value = Value::Dictionary {
                 is_weak: is_weak,
@@ -535,6 +1023,10 @@ where
 
     fn decode_value(&mut self) -> DecodeResult<Value> {
         let marker = try!(self.reader.read_u8());
+        self.decode_value_from_marker(marker)
+    }
+
+    fn decode_value_from_marker(&mut self, marker: u8) -> DecodeResult<Value> {
         match marker {
             Marker::UNDEFINED => Ok(Value::Undefined),
             Marker::NULL => Ok(Value::Null),
@@ -555,333 +1047,2248 @@ where
             Marker::VECTOR_OBJECT => self.decode_vector_object(),
             Marker::DICTIONARY => self.decode_dictionary(),
 
-            _ => Err(DecodeError::UnknownType { marker }),
+            _ => Err(DecodeErrorKind::UnknownType { marker }.into()),
         }
     }
 }
 
-#[derive(Debug)]
-pub struct Encoder<W> {
-    writer: W,
+/// Borrowed counterpart of [`Value`], returned by [`read_borrowed`]. Its
+/// string-bearing variants hold `Cow<'a, str>`/`Cow<'a, [u8]>` slices into
+/// the source buffer rather than freshly allocated `String`s/`Vec<u8>`s, so
+/// decoding a high-volume RTMP command payload doesn't allocate once per
+/// string. Decoded strings and byte arrays are always `Cow::Borrowed`;
+/// string bytes that aren't valid UTF-8 fail with `DecodeErrorKind::String`,
+/// as they do in `Decoder`. Call `.to_owned()` to lift a `ValueRef`
+/// into an ordinary `Value` once it needs to outlive `buf`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ValueRef<'a> {
+    Undefined,
+    Null,
+    Boolean(bool),
+    Integer(i32),
+    Double(f64),
+    String(Cow<'a, str>),
+    XmlDoc(Cow<'a, str>),
+    Date { unixtime: time::Duration },
+    Object {
+        name: Option<Cow<'a, str>>,
+        sealed_count: usize,
+        pairs: Vec<Pair<Cow<'a, str>, ValueRef<'a>>>,
+    },
+    Xml(Cow<'a, str>),
+    Array {
+        assoc_entries: Vec<Pair<Cow<'a, str>, ValueRef<'a>>>,
+        dense_entries: Vec<ValueRef<'a>>,
+    },
+    ByteArray(Cow<'a, [u8]>),
+    IntVector { is_fixed: bool, entries: Vec<i32> },
+    UintVector { is_fixed: bool, entries: Vec<u32> },
+    DoubleVector { is_fixed: bool, entries: Vec<f64> },
+    ObjectVector {
+        name: Option<Cow<'a, str>>,
+        is_fixed: bool,
+        entries: Vec<ValueRef<'a>>,
+    },
+    Dictionary {
+        is_weak: bool,
+        entries: Vec<Pair<ValueRef<'a>, ValueRef<'a>>>,
+    },
 }
 
-impl<W> Encoder<W>
-where
-    W: io::Write,
-{
-    pub fn new(writer: W) -> Self {
-        Encoder { writer: writer }
-    }
-
-    pub fn encode(&mut self, value: &Value) -> EncodeResult<()> {
-        self.encode_value(value)
-    }
-
-    // 1.3.1 Variable Length Unsigned 29-bit Integer Encoding
-    // AMF 3 makes use of a special compact format for writing integers to reduce the number of bytes required for encoding. As with a normal 32-bit integer, up to 4 bytes are required to hold the value however the high bit of the first 3 bytes are used as flags to determine whether the next byte is part of the integer. With up to 3 bits of the 32 bits being used as flags, only 29 significant bits remain for encoding an integer. This means the largest unsigned integer value that can be represented is 229 - 1.
-    // (hex)
-    // 0x00000000 - 0x0000007F
-    // 0x00000080 - 0x00003FFF
-    // 0x00004000 - 0x001FFFFF
-    // 0x00200000 - 0x3FFFFFFF
-    // 0x40000000 - 0xFFFFFFFF
-    // : (binary)
-    // :  0xxxxxxx
-    // :  1xxxxxxx 0xxxxxxx
-    // :  1xxxxxxx 1xxxxxxx 0xxxxxxx
-    // :  1xxxxxxx 1xxxxxxx 1xxxxxxx xxxxxxxx
-    // :  throw range exception
-    // In ABNF syntax, the variable length unsigned 29-bit integer type is described as follows:
-    // U29 = U29-1 | U29-2 | U29-3 | U29-4
-    // U29-1 = %x00-7F
-    // U29-2 = %x80-FF %x00-7F
-    // U29-3 = %x80-FF %x80-FF %x00-7F
-    // U29-4 = %x80-FF %x80-FF %x80-FF %x00-FF
-    fn encode_u29(&mut self, u29: u32) -> EncodeResult<()> {
-        if u29 < 0x80 {
-            // U29-1
-            try!(self.writer.write_u8(u29 as u8));
-        } else if u29 < 0x4000 {
-            // U29-2
-            let b1 = (u29 >> 7 | 0x80) as u8;
-            let b2 = (u29 & 0x7F) as u8;
-            for b in &[b1, b2] {
-                try!(self.writer.write_u8(*b));
+impl<'a> ValueRef<'a> {
+    /// Lifts this borrowed value into an owned [`Value`], copying any
+    /// strings/byte-strings that are still borrowed from the source buffer.
+    pub fn to_owned(&self) -> Value {
+        match *self {
+            ValueRef::Undefined => Value::Undefined,
+            ValueRef::Null => Value::Null,
+            ValueRef::Boolean(b) => Value::Boolean(b),
+            ValueRef::Integer(n) => Value::Integer(n),
+            ValueRef::Double(n) => Value::Double(n),
+            ValueRef::String(ref s) => Value::String(s.clone().into_owned()),
+            ValueRef::XmlDoc(ref s) => Value::XmlDoc(s.clone().into_owned()),
+            ValueRef::Date { unixtime } => Value::Date { unixtime: unixtime },
+            ValueRef::Object { ref name, sealed_count, ref pairs } => {
+                Value::Object {
+                    name: name.as_ref().map(|n| n.clone().into_owned()),
+                    sealed_count: sealed_count,
+                    pairs: pairs
+                        .iter()
+                        .map(|p| {
+                            Pair {
+                                key: p.key.clone().into_owned(),
+                                value: p.value.to_owned(),
+                            }
+                        })
+                        .collect(),
+                }
             }
-        } else if u29 > 0x3FFF && u29 <= 0x1FFFFF {
-            // U29-3
-            let b1 = (u29 >> 14 | 0x80) as u8;
-            let b2 = (((u29 >> 7) & 0x7F) | 0x80) as u8;
-            let b3 = (u29 & 0x7F) as u8;
-            for b in &[b1, b2, b3] {
-                try!(self.writer.write_u8(*b));
+            ValueRef::Xml(ref s) => Value::Xml(s.clone().into_owned()),
+            ValueRef::Array { ref assoc_entries, ref dense_entries } => {
+                Value::Array {
+                    assoc_entries: assoc_entries
+                        .iter()
+                        .map(|p| {
+                            Pair {
+                                key: p.key.clone().into_owned(),
+                                value: p.value.to_owned(),
+                            }
+                        })
+                        .collect(),
+                    dense_entries: dense_entries.iter().map(|v| v.to_owned()).collect(),
+                }
+            }
+            ValueRef::ByteArray(ref b) => Value::ByteArray(b.clone().into_owned()),
+            ValueRef::IntVector { is_fixed, ref entries } => {
+                Value::IntVector {
+                    is_fixed: is_fixed,
+                    entries: entries.clone(),
+                }
             }
-        } else if u29 < 0x4000_0000 {
-            // U29-4
-            let b1 = (u29 >> 22 | 0x80) as u8;
-            let b2 = (((u29 >> 15) & 0x7F) | 0x80) as u8;
-            let b3 = (((u29 >> 8) & 0x7F) | 0x80) as u8;
-            let b4 = (u29 & 0xFF) as u8;
-            for b in &[b1, b2, b3, b4] {
-                try!(self.writer.write_u8(*b));
+            ValueRef::UintVector { is_fixed, ref entries } => {
+                Value::UintVector {
+                    is_fixed: is_fixed,
+                    entries: entries.clone(),
+                }
+            }
+            ValueRef::DoubleVector { is_fixed, ref entries } => {
+                Value::DoubleVector {
+                    is_fixed: is_fixed,
+                    entries: entries.clone(),
+                }
+            }
+            ValueRef::ObjectVector { ref name, is_fixed, ref entries } => {
+                Value::ObjectVector {
+                    name: name.as_ref().map(|n| n.clone().into_owned()),
+                    is_fixed: is_fixed,
+                    entries: entries.iter().map(|v| v.to_owned()).collect(),
+                }
+            }
+            ValueRef::Dictionary { is_weak, ref entries } => {
+                Value::Dictionary {
+                    is_weak: is_weak,
+                    entries: entries
+                        .iter()
+                        .map(|p| {
+                            Pair {
+                                key: p.key.to_owned(),
+                                value: p.value.to_owned(),
+                            }
+                        })
+                        .collect(),
+                }
             }
-        } else {
-            return Err(EncodeError::U29Overflow { u29 });
         }
-        Ok(())
     }
+}
 
-    // TODO: reference tableのサポート
-    fn encode_utf8(&mut self, s: &str) -> EncodeResult<()> {
-        let size = ((s.len() << 1) | 0x01) as u32;
-        try!(self.encode_u29(size));
+#[derive(Debug, Clone)]
+struct BorrowedClass<'a> {
+    name: Option<Cow<'a, str>>,
+    is_dynamic: bool,
+    fields: Vec<Cow<'a, str>>,
+}
 
-        try!(self.writer.write_all(s.as_bytes()));
-        Ok(())
-    }
+/// Decodes a single AMF3 value out of `buf` without allocating a
+/// `String`/`Vec<u8>` per string- or byte-array-bearing field, in the
+/// spirit of zero-copy formats like `rkyv`. Every externalizable class is
+/// rejected with `DecodeErrorKind::ExternalizableType`, including the Flex
+/// collection wrappers that `Decoder::new` decodes by default. Unbounded recursion/
+/// collection/string limits, same as a bare `Decoder::new`; use
+/// [`BorrowedDecoder`] to bound them against untrusted input.
+pub fn read_borrowed<'a>(buf: &'a [u8]) -> DecodeResult<ValueRef<'a>> {
+    BorrowedDecoder::new(buf).decode()
+}
 
-    fn encode_pairs(&mut self, pairs: &[Pair<String, Value>]) -> EncodeResult<()> {
-        for pair in pairs {
-            try!(self.encode_utf8(&pair.key));
-            try!(self.encode(&pair.value));
+/// Alias for [`read_borrowed`], named to match the `decode_ref` naming used
+/// elsewhere for the borrowed-decode entry point.
+pub fn decode_ref<'a>(buf: &'a [u8]) -> DecodeResult<ValueRef<'a>> {
+    read_borrowed(buf)
+}
+
+/// Builder for [`read_borrowed`] that can bound recursion depth and
+/// collection/string size, mirroring `Decoder::with_max_depth`/
+/// `with_max_collection_len`/`with_max_string_len` for this zero-copy entry
+/// point.
+pub struct BorrowedDecoder<'a> {
+    buf: &'a [u8],
+    max_depth: usize,
+    max_collection_len: usize,
+    max_string_len: usize,
+}
+
+impl<'a> BorrowedDecoder<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        BorrowedDecoder {
+            buf: buf,
+            max_depth: usize::max_value(),
+            max_collection_len: usize::max_value(),
+            max_string_len: usize::max_value(),
         }
-        try!(self.encode_utf8("")); // UTF-8-empty
-        Ok(())
     }
 
-    fn encode_boolean(&mut self, boolean: bool) -> EncodeResult<()> {
-        if boolean {
-            try!(self.writer.write_u8(Marker::TRUE));
-        } else {
-            try!(self.writer.write_u8(Marker::FALSE));
-        }
-        Ok(())
+    /// Bounds how deeply objects/arrays/vectors/dictionaries may nest while
+    /// decoding, guarding against stack exhaustion from hostile input.
+    /// Unlimited by default.
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
     }
 
-    fn encode_integer(&mut self, integer: i32) -> EncodeResult<()> {
-        try!(self.writer.write_u8(Marker::INTEGER));
-        let u29 = if integer >= 0 {
-            integer as u32
-        } else {
-            ((1 << 29) + integer) as u32
-        };
-        try!(self.encode_u29(u29));
-        Ok(())
+    /// Bounds the element/associative-pair/trait-field count a single
+    /// object, array, byte array, vector, or dictionary may declare,
+    /// guarding against memory exhaustion from a forged length. Unlimited by
+    /// default.
+    pub fn with_max_collection_len(mut self, max_collection_len: usize) -> Self {
+        self.max_collection_len = max_collection_len;
+        self
     }
 
-    fn encode_double(&mut self, double: f64) -> EncodeResult<()> {
-        try!(self.writer.write_u8(Marker::DOUBLE));
-        try!(self.writer.write_f64::<BigEndian>(double));
-        Ok(())
+    /// Bounds the byte length a single inline string (including object
+    /// keys, class names, and XML payloads) may declare, guarding against
+    /// memory exhaustion from a forged length. Byte arrays are bounded by
+    /// `with_max_collection_len` instead. Unlimited by default.
+    pub fn with_max_string_len(mut self, max_string_len: usize) -> Self {
+        self.max_string_len = max_string_len;
+        self
     }
 
-    fn encode_string(&mut self, string: &str) -> EncodeResult<()> {
-        try!(self.writer.write_u8(Marker::STRING));
-        try!(self.encode_utf8(string));
-        Ok(())
+    pub fn decode(self) -> DecodeResult<ValueRef<'a>> {
+        BorrowedCursor {
+            remaining: self.buf,
+            objects: Vec::new(),
+            strings: Vec::new(),
+            classes: Vec::new(),
+            max_depth: self.max_depth,
+            max_collection_len: self.max_collection_len,
+            max_string_len: self.max_string_len,
+            depth: 0,
+        }.decode_value()
     }
+}
 
-    fn encode_xml_document(&mut self, xml_doc: &str) -> EncodeResult<()> {
-        try!(self.writer.write_u8(Marker::XML_DOC));
-        try!(self.encode_utf8(xml_doc));
+struct BorrowedCursor<'a> {
+    remaining: &'a [u8],
+    objects: Vec<ValueRef<'a>>,
+    strings: Vec<Cow<'a, str>>,
+    classes: Vec<BorrowedClass<'a>>,
+    max_depth: usize,
+    max_collection_len: usize,
+    max_string_len: usize,
+    depth: usize,
+}
+
+impl<'a> BorrowedCursor<'a> {
+    fn enter_nested(&mut self) -> DecodeResult<()> {
+        self.depth += 1;
+        if self.depth > self.max_depth {
+            return Err(DecodeErrorKind::DepthLimitExceeded { limit: self.max_depth }.into());
+        }
         Ok(())
     }
 
-    fn encode_date(&mut self, unixtime: time::Duration) -> EncodeResult<()> {
-        let ms = unixtime.as_secs() * 1000 + (unixtime.subsec_nanos() as u64) / 1000_000;
-        try!(self.writer.write_u8(Marker::DATE));
-        let size = ((0 << 1) | 0x01) as u32;
-        try!(self.encode_u29(size));
-        try!(self.writer.write_f64::<BigEndian>(ms as f64));
+    fn exit_nested(&mut self) {
+        self.depth -= 1;
+    }
+
+    fn check_collection_len(&self, len: usize) -> DecodeResult<()> {
+        if len > self.max_collection_len {
+            return Err(DecodeErrorKind::CollectionTooLarge { len: len }.into());
+        }
         Ok(())
     }
 
-    fn encode_xml(&mut self, xml: &str) -> EncodeResult<()> {
-        try!(self.writer.write_u8(Marker::XML));
-        try!(self.encode_utf8(xml));
+    fn check_string_len(&self, len: usize) -> DecodeResult<()> {
+        if len > self.max_string_len {
+            return Err(DecodeErrorKind::StringTooLong { len: len }.into());
+        }
         Ok(())
     }
 
-    // TODO: reference tableのサポート
-    fn encode_object(
-        &mut self,
-        name: &Option<String>,
-        sealed_count: usize,
-        pairs: &[Pair<String, Value>],
-    ) -> EncodeResult<()> {
-        try!(self.writer.write_u8(Marker::OBJECT));
+    fn take(&mut self, len: usize) -> DecodeResult<&'a [u8]> {
+        if len > self.remaining.len() {
+            return Err(DecodeError::from(io::Error::from(io::ErrorKind::UnexpectedEof)));
+        }
+        let (head, tail) = self.remaining.split_at(len);
+        self.remaining = tail;
+        Ok(head)
+    }
 
-        let is_reference = 1 as usize;
-        let is_externalizable = false as usize;
-        let is_dynamic = (sealed_count < pairs.len()) as usize;
-        let u29 = (sealed_count << 3) | (is_dynamic << 2) | (is_externalizable << 1) | is_reference;
-        let size = ((u29 << 1) | 0x01) as u32;
-        try!(self.encode_u29(size));
+    fn decode_u29(&mut self) -> DecodeResult<u32> {
+        read_u29(&mut self.remaining)
+    }
 
-        let name = name.as_ref().map_or("", |s| &s);
-        try!(self.encode_utf8(name));
-        for pair in pairs.iter().take(sealed_count) {
-            try!(self.encode_utf8(&pair.key));
+    fn decode_utf8(&mut self) -> DecodeResult<Cow<'a, str>> {
+        let u29 = try!(self.decode_u29()) as usize;
+        let is_reference = (u29 & 0x1) == 0;
+
+        if is_reference {
+            let index = u29 >> 1;
+            self.strings
+                .get(index)
+                .cloned()
+                .ok_or(DecodeErrorKind::NotFoundInReferenceTable { index: index }.into())
+        } else {
+            let size = u29 >> 1;
+            try!(self.check_string_len(size));
+            let bytes = try!(self.take(size));
+            let s = match str::from_utf8(bytes) {
+                Ok(s) => Cow::Borrowed(s),
+                Err(_) => Cow::Owned(try!(String::from_utf8(bytes.to_vec()))),
+            };
+            if !s.is_empty() {
+                self.strings.push(s.clone());
+            }
+            Ok(s)
         }
+    }
 
-        for pair in pairs.iter().take(sealed_count) {
-            try!(self.encode(&pair.value));
+    fn decode_pairs(&mut self) -> DecodeResult<Vec<Pair<Cow<'a, str>, ValueRef<'a>>>> {
+        let mut pairs = Vec::new();
+        loop {
+            let key = try!(self.decode_utf8());
+            if key.is_empty() {
+                return Ok(pairs);
+            }
+            let value = try!(self.decode_value());
+            pairs.push(Pair {
+                key: key,
+                value: value,
+            });
         }
+    }
 
-        if pairs.len() > sealed_count {
-            try!(self.encode_pairs(&pairs[sealed_count..]));
+    fn decode_integer(&mut self) -> DecodeResult<ValueRef<'a>> {
+        let num = try!(self.decode_u29()) as i32;
+        let num = if num >= (1 << 28) {
+            num - (1 << 29)
+        } else {
+            num
+        };
+        Ok(ValueRef::Integer(num))
+    }
+
+    fn decode_xml_doc(&mut self) -> DecodeResult<ValueRef<'a>> {
+        let u29 = try!(self.decode_u29()) as usize;
+        let is_reference = (u29 & 0x1) == 0;
+
+        if is_reference {
+            let index = u29 >> 1;
+            return self.objects
+                .get(index)
+                .cloned()
+                .ok_or(DecodeErrorKind::NotFoundInReferenceTable { index: index }.into());
         }
 
-        Ok(())
+        let index = self.objects.len();
+        self.objects.push(ValueRef::Null);
+
+        let size = u29 >> 1;
+        try!(self.check_string_len(size));
+        let bytes = try!(self.take(size));
+        let s = match str::from_utf8(bytes) {
+            Ok(s) => Cow::Borrowed(s),
+            Err(_) => Cow::Owned(try!(String::from_utf8(bytes.to_vec()))),
+        };
+        let value = ValueRef::XmlDoc(s);
+
+        self.objects[index] = value.clone();
+        Ok(value)
     }
 
-    // TODO: reference tableのサポート
-    fn encode_array(&mut self, assoc: &[Pair<String, Value>], dense: &[Value]) -> EncodeResult<()> {
-        try!(self.writer.write_u8(Marker::ARRAY));
-        let size = ((dense.len() << 1) | 0x01) as u32;
-        try!(self.encode_u29(size));
-        try!(self.encode_pairs(assoc));
-        try!(
-            dense
-                .iter()
-                .map(|v| self.encode(v))
-                .collect::<EncodeResult<Vec<_>>>()
-        );
-        Ok(())
+    fn decode_xml(&mut self) -> DecodeResult<ValueRef<'a>> {
+        let u29 = try!(self.decode_u29()) as usize;
+        let is_reference = (u29 & 0x1) == 0;
+
+        if is_reference {
+            let index = u29 >> 1;
+            return self.objects
+                .get(index)
+                .cloned()
+                .ok_or(DecodeErrorKind::NotFoundInReferenceTable { index: index }.into());
+        }
+
+        let index = self.objects.len();
+        self.objects.push(ValueRef::Null);
+
+        let size = u29 >> 1;
+        try!(self.check_string_len(size));
+        let bytes = try!(self.take(size));
+        let s = match str::from_utf8(bytes) {
+            Ok(s) => Cow::Borrowed(s),
+            Err(_) => Cow::Owned(try!(String::from_utf8(bytes.to_vec()))),
+        };
+        let value = ValueRef::Xml(s);
+
+        self.objects[index] = value.clone();
+        Ok(value)
     }
 
-    // TODO: reference tableのサポート
-    fn encode_byte_array(&mut self, bytes: &[u8]) -> EncodeResult<()> {
-        try!(self.writer.write_u8(Marker::BYTE_ARRAY));
-        let size = ((bytes.len() << 1) | 0x01) as u32;
-        try!(self.encode_u29(size));
-        try!(self.writer.write_all(bytes));
-        Ok(())
+    fn decode_date(&mut self) -> DecodeResult<ValueRef<'a>> {
+        try!(self.decode_u29()) as usize; // skip, as in `Decoder::decode_date`
+        let millis = try!(self.remaining.read_f64::<BigEndian>());
+        Ok(ValueRef::Date { unixtime: time::Duration::from_millis(millis as u64) })
     }
 
-    // TODO: reference tableのサポート
-    fn encode_vector_int(&mut self, is_fixed: bool, vec: &[i32]) -> EncodeResult<()> {
-        try!(self.writer.write_u8(Marker::VECTOR_INT));
-        let size = ((vec.len() << 1) | 0x01) as u32;
-        try!(self.encode_u29(size));
-        try!(self.writer.write_u8(is_fixed as u8));
-        for &v in vec {
-            try!(self.writer.write_i32::<BigEndian>(v));
+    fn decode_object(&mut self) -> DecodeResult<ValueRef<'a>> {
+        let u29 = try!(self.decode_u29()) as usize;
+        let is_reference = (u29 & 0x1) == 0;
+
+        if is_reference {
+            let index = u29 >> 1;
+            return self.objects
+                .get(index)
+                .cloned()
+                .ok_or(DecodeErrorKind::NotFoundInReferenceTable { index: index }.into());
         }
-        Ok(())
+
+        let index = self.objects.len();
+        self.objects.push(ValueRef::Null);
+
+        let size = u29 >> 1;
+        let value = if (size & 0x1) == 0 {
+            let klass_index = size >> 0x1;
+            let klass = try!(
+                self.classes
+                    .get(klass_index)
+                    .cloned()
+                    .ok_or(DecodeError::from(DecodeErrorKind::NotFoundInReferenceTable { index: klass_index }))
+            );
+
+            try!(self.enter_nested());
+            let pairs: DecodeResult<_> = (|| {
+                let mut pairs = try!(
+                    klass
+                        .fields
+                        .iter()
+                        .map(|k| {
+                            Ok(Pair {
+                                key: k.clone(),
+                                value: try!(self.decode_value()),
+                            })
+                        })
+                        .collect::<DecodeResult<Vec<_>>>()
+                );
+
+                if klass.is_dynamic {
+                    pairs.extend(try!(self.decode_pairs()));
+                }
+                Ok(pairs)
+            })();
+            self.exit_nested();
+            let pairs = try!(pairs);
+            ValueRef::Object {
+                name: klass.name,
+                sealed_count: pairs.len(),
+                pairs: pairs,
+            }
+        } else if (size & 0b10) != 0 {
+            let class_name = try!(self.decode_utf8());
+            return Err(DecodeErrorKind::ExternalizableType { name: class_name.into_owned() }.into());
+        } else {
+            let is_dynamic = (size & 0b100) != 0;
+            let field_num = size >> 3;
+            try!(self.check_collection_len(field_num));
+            let class_name = try!(self.decode_utf8());
+            let fields = try!((0..field_num).map(|_| self.decode_utf8()).collect());
+
+            let klass = BorrowedClass {
+                name: if class_name.is_empty() {
+                    None
+                } else {
+                    Some(class_name)
+                },
+                is_dynamic: is_dynamic,
+                fields: fields,
+            };
+            self.classes.push(klass.clone());
+            try!(self.enter_nested());
+            let pairs: DecodeResult<_> = (|| {
+                let mut pairs = try!(
+                    klass
+                        .fields
+                        .iter()
+                        .map(|k| {
+                            Ok(Pair {
+                                key: k.clone(),
+                                value: try!(self.decode_value()),
+                            })
+                        })
+                        .collect::<DecodeResult<Vec<_>>>()
+                );
+                if klass.is_dynamic {
+                    pairs.extend(try!(self.decode_pairs()));
+                }
+                Ok(pairs)
+            })();
+            self.exit_nested();
+            let pairs = try!(pairs);
+            ValueRef::Object {
+                name: klass.name,
+                sealed_count: pairs.len(),
+                pairs: pairs,
+            }
+        };
+
+        self.objects[index] = value.clone();
+        Ok(value)
     }
 
-    // TODO: reference tableのサポート
-    fn encode_vector_uint(&mut self, is_fixed: bool, vec: &[u32]) -> EncodeResult<()> {
-        try!(self.writer.write_u8(Marker::VECTOR_UINT));
-        let size = ((vec.len() << 1) | 0x01) as u32;
-        try!(self.encode_u29(size));
-        try!(self.writer.write_u8(is_fixed as u8));
-        for &v in vec {
-            try!(self.writer.write_u32::<BigEndian>(v));
+    fn decode_array(&mut self) -> DecodeResult<ValueRef<'a>> {
+        let u29 = try!(self.decode_u29()) as usize;
+        let is_reference = (u29 & 0x01) == 0;
+
+        if is_reference {
+            let index = u29 >> 1;
+            return self.objects
+                .get(index)
+                .cloned()
+                .ok_or(DecodeErrorKind::NotFoundInReferenceTable { index: index }.into());
         }
-        Ok(())
+
+        let index = self.objects.len();
+        self.objects.push(ValueRef::Null);
+
+        let size = u29 >> 1;
+        try!(self.check_collection_len(size));
+        try!(self.enter_nested());
+        let entries: DecodeResult<_> = (|| {
+            let assoc = try!(self.decode_pairs());
+            let dense = try!((0..size).map(|_| self.decode_value()).collect());
+            Ok((assoc, dense))
+        })();
+        self.exit_nested();
+        let (assoc, dense) = try!(entries);
+
+        let value = ValueRef::Array {
+            assoc_entries: assoc,
+            dense_entries: dense,
+        };
+
+        self.objects[index] = value.clone();
+        Ok(value)
     }
 
-    // TODO: reference tableのサポート
-    fn encode_vector_double(&mut self, is_fixed: bool, vec: &[f64]) -> EncodeResult<()> {
-        try!(self.writer.write_u8(Marker::VECTOR_DOUBLE));
-        let size = ((vec.len() << 1) | 0x01) as u32;
-        try!(self.encode_u29(size));
-        try!(self.writer.write_u8(is_fixed as u8));
-        for &v in vec {
-            try!(self.writer.write_f64::<BigEndian>(v));
+    fn decode_byte_array(&mut self) -> DecodeResult<ValueRef<'a>> {
+        let u29 = try!(self.decode_u29()) as usize;
+        let is_reference = (u29 & 0x01) == 0;
+
+        if is_reference {
+            let index = u29 >> 1;
+            return self.objects
+                .get(index)
+                .cloned()
+                .ok_or(DecodeErrorKind::NotFoundInReferenceTable { index: index }.into());
         }
-        Ok(())
+
+        let index = self.objects.len();
+        self.objects.push(ValueRef::Null);
+
+        let size = u29 >> 1;
+        try!(self.check_collection_len(size));
+        let value = ValueRef::ByteArray(Cow::Borrowed(try!(self.take(size))));
+
+        self.objects[index] = value.clone();
+        Ok(value)
     }
 
-    // TODO: reference tableのサポート
-    fn encode_vector_object(
-        &mut self,
-        name: &Option<String>,
-        is_fixed: bool,
-        vec: &[Value],
-    ) -> EncodeResult<()> {
-        try!(self.writer.write_u8(Marker::VECTOR_OBJECT));
-        let size = ((vec.len() << 1) | 0x01) as u32;
-        try!(self.encode_u29(size));
-        try!(self.writer.write_u8(is_fixed as u8));
-        try!(self.encode_utf8(name.as_ref().map_or("*", |s| &s)));
-        for v in vec {
-            try!(self.encode(v));
+    fn decode_vector_int(&mut self) -> DecodeResult<ValueRef<'a>> {
+        let u29 = try!(self.decode_u29()) as usize;
+        let is_reference = (u29 & 0x01) == 0;
+
+        if is_reference {
+            let index = u29 >> 1;
+            return self.objects
+                .get(index)
+                .cloned()
+                .ok_or(DecodeErrorKind::NotFoundInReferenceTable { index: index }.into());
         }
-        Ok(())
+
+        let index = self.objects.len();
+        self.objects.push(ValueRef::Null);
+
+        let size = u29 >> 1;
+        try!(self.check_collection_len(size));
+        let is_fixed = try!(self.remaining.read_u8()) != 0;
+        let entries = try!(
+            (0..size)
+                .map(|_| self.remaining.read_i32::<BigEndian>())
+                .collect()
+        );
+
+        let value = ValueRef::IntVector {
+            is_fixed: is_fixed,
+            entries: entries,
+        };
+
+        self.objects[index] = value.clone();
+        Ok(value)
     }
 
-    // TODO: reference tableのサポート
-    fn encode_dictionary(
-        &mut self,
-        is_weak: bool,
-        pairs: &[Pair<Value, Value>],
-    ) -> EncodeResult<()> {
-        try!(self.writer.write_u8(Marker::DICTIONARY));
-        let size = ((pairs.len() << 1) | 0x01) as u32;
-        try!(self.encode_u29(size));
-        try!(self.writer.write_u8(is_weak as u8));
-        for pair in pairs {
-            try!(self.encode(&pair.key));
-            try!(self.encode(&pair.value));
+    fn decode_vector_uint(&mut self) -> DecodeResult<ValueRef<'a>> {
+        let u29 = try!(self.decode_u29()) as usize;
+        let is_reference = (u29 & 0x01) == 0;
+
+        if is_reference {
+            let index = u29 >> 1;
+            return self.objects
+                .get(index)
+                .cloned()
+                .ok_or(DecodeErrorKind::NotFoundInReferenceTable { index: index }.into());
+        }
+
+        let index = self.objects.len();
+        self.objects.push(ValueRef::Null);
+
+        let size = u29 >> 1;
+        try!(self.check_collection_len(size));
+        let is_fixed = try!(self.remaining.read_u8()) != 0;
+        let entries = try!(
+            (0..size)
+                .map(|_| self.remaining.read_u32::<BigEndian>())
+                .collect()
+        );
+
+        let value = ValueRef::UintVector {
+            is_fixed: is_fixed,
+            entries: entries,
+        };
+
+        self.objects[index] = value.clone();
+        Ok(value)
+    }
+
+    fn decode_vector_double(&mut self) -> DecodeResult<ValueRef<'a>> {
+        let u29 = try!(self.decode_u29()) as usize;
+        let is_reference = (u29 & 0x01) == 0;
+
+        if is_reference {
+            let index = u29 >> 1;
+            return self.objects
+                .get(index)
+                .cloned()
+                .ok_or(DecodeErrorKind::NotFoundInReferenceTable { index: index }.into());
+        }
+
+        let index = self.objects.len();
+        self.objects.push(ValueRef::Null);
+
+        let size = u29 >> 1;
+        try!(self.check_collection_len(size));
+        let is_fixed = try!(self.remaining.read_u8()) != 0;
+        let entries = try!(
+            (0..size)
+                .map(|_| self.remaining.read_f64::<BigEndian>())
+                .collect()
+        );
+
+        let value = ValueRef::DoubleVector {
+            is_fixed: is_fixed,
+            entries: entries,
+        };
+
+        self.objects[index] = value.clone();
+        Ok(value)
+    }
+
+    fn decode_vector_object(&mut self) -> DecodeResult<ValueRef<'a>> {
+        let u29 = try!(self.decode_u29()) as usize;
+        let is_reference = (u29 & 0x01) == 0;
+
+        if is_reference {
+            let index = u29 >> 1;
+            return self.objects
+                .get(index)
+                .cloned()
+                .ok_or(DecodeErrorKind::NotFoundInReferenceTable { index: index }.into());
+        }
+
+        let index = self.objects.len();
+        self.objects.push(ValueRef::Null);
+
+        let size = u29 >> 1;
+        try!(self.check_collection_len(size));
+        let is_fixed = try!(self.remaining.read_u8()) != 0;
+        let name = try!(self.decode_utf8());
+        try!(self.enter_nested());
+        let entries = (0..size).map(|_| self.decode_value()).collect();
+        self.exit_nested();
+        let entries = try!(entries);
+
+        let value = ValueRef::ObjectVector {
+            name: if name == "*" { None } else { Some(name) },
+            is_fixed: is_fixed,
+            entries: entries,
+        };
+
+        self.objects[index] = value.clone();
+        Ok(value)
+    }
+
+    fn decode_dictionary(&mut self) -> DecodeResult<ValueRef<'a>> {
+        let u29 = try!(self.decode_u29()) as usize;
+        let is_reference = (u29 & 0x01) == 0;
+
+        if is_reference {
+            let index = u29 >> 1;
+            return self.objects
+                .get(index)
+                .cloned()
+                .ok_or(DecodeErrorKind::NotFoundInReferenceTable { index: index }.into());
+        }
+
+        let index = self.objects.len();
+        self.objects.push(ValueRef::Null);
+
+        let size = u29 >> 1;
+        try!(self.check_collection_len(size));
+        let is_weak = try!(self.remaining.read_u8()) == 1;
+        try!(self.enter_nested());
+        let entries = (0..size)
+            .map(|_| {
+                Ok(Pair {
+                    key: try!(self.decode_value()),
+                    value: try!(self.decode_value()),
+                })
+            })
+            .collect::<DecodeResult<_>>();
+        self.exit_nested();
+        let entries = try!(entries);
+
+        let value = ValueRef::Dictionary {
+            is_weak: is_weak,
+            entries: entries,
+        };
+
+        self.objects[index] = value.clone();
+        Ok(value)
+    }
+
+    fn decode_value(&mut self) -> DecodeResult<ValueRef<'a>> {
+        let marker = try!(self.remaining.read_u8());
+        match marker {
+            Marker::UNDEFINED => Ok(ValueRef::Undefined),
+            Marker::NULL => Ok(ValueRef::Null),
+            Marker::FALSE => Ok(ValueRef::Boolean(false)),
+            Marker::TRUE => Ok(ValueRef::Boolean(true)),
+            Marker::INTEGER => self.decode_integer(),
+            Marker::DOUBLE => Ok(ValueRef::Double(try!(self.remaining.read_f64::<BigEndian>()))),
+            Marker::STRING => Ok(ValueRef::String(try!(self.decode_utf8()))),
+            Marker::XML_DOC => self.decode_xml_doc(),
+            Marker::DATE => self.decode_date(),
+            Marker::XML => self.decode_xml(),
+            Marker::ARRAY => self.decode_array(),
+            Marker::BYTE_ARRAY => self.decode_byte_array(),
+            Marker::OBJECT => self.decode_object(),
+            Marker::VECTOR_INT => self.decode_vector_int(),
+            Marker::VECTOR_UINT => self.decode_vector_uint(),
+            Marker::VECTOR_DOUBLE => self.decode_vector_double(),
+            Marker::VECTOR_OBJECT => self.decode_vector_object(),
+            Marker::DICTIONARY => self.decode_dictionary(),
+
+            _ => Err(DecodeErrorKind::UnknownType { marker }.into()),
+        }
+    }
+}
+
+/// Iterator returned by `Decoder::values`.
+pub struct Values<'a, R: 'a> {
+    decoder: &'a mut Decoder<R>,
+}
+
+impl<'a, R> Iterator for Values<'a, R>
+where
+    R: io::Read,
+{
+    type Item = DecodeResult<Value>;
+
+    fn next(&mut self) -> Option<DecodeResult<Value>> {
+        let marker = match self.decoder.reader.read_u8() {
+            Ok(m) => m,
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return None,
+            Err(e) => {
+                return Some(Err(DecodeError::from(e).with_offset(self.decoder.reader.offset())))
+            }
+        };
+        Some(
+            self.decoder
+                .decode_value_from_marker(marker)
+                .map_err(|e| e.with_offset(self.decoder.reader.offset())),
+        )
+    }
+}
+
+#[derive(Debug)]
+pub struct Encoder<W> {
+    writer: CountingWriter<W>,
+    use_references: bool,
+    strings: Vec<String>,
+    objects: Vec<Value>,
+    traits: Vec<Class>,
+}
+
+impl<W> Encoder<W>
+where
+    W: io::Write,
+{
+    pub fn new(writer: W) -> Self {
+        Encoder {
+            writer: CountingWriter::new(writer),
+            use_references: false,
+            strings: Vec::new(),
+            objects: Vec::new(),
+            traits: Vec::new(),
+        }
+    }
+
+    /// Enables reference-table deduplication: repeated strings are emitted as
+    /// a back-reference into the string table, and repeated objects/arrays/
+    /// byte arrays/vectors/dictionaries/Xml/XmlDoc — plus their traits — as a
+    /// back-reference into their own tables, instead of being inlined again.
+    /// This is the encode-side half of AMF3's reference-table mechanism;
+    /// `Decoder`'s `strings`/`objects`/`classes` tables on the decode side
+    /// have resolved references unconditionally since before this type
+    /// existed.
+    pub fn with_references(mut self) -> Self {
+        self.use_references = true;
+        self
+    }
+
+    /// The number of bytes written to the underlying writer so far, i.e. the
+    /// absolute offset an error occurring right now would carry.
+    pub fn offset(&self) -> u64 {
+        self.writer.offset()
+    }
+
+    pub fn encode(&mut self, value: &Value) -> EncodeResult<()> {
+        self.strings.clear();
+        self.objects.clear();
+        self.traits.clear();
+        self.encode_value(value).map_err(|e| e.with_offset(self.writer.offset()))
+    }
+
+    fn encode_u29(&mut self, u29: u32) -> EncodeResult<()> {
+        write_u29(&mut self.writer, u29)
+    }
+
+    // Checks the object table for a structurally-equal complex value.
+    // Returns `Ok(true)` when a reference header was written in place of the
+    // value (the caller must not encode the body), `Ok(false)` otherwise. On
+    // a miss, the value is pushed into the table *before* its body is
+    // encoded, so indices line up with the order `Decoder::decode_object` et
+    // al. assign them.
+    fn try_write_value_reference(&mut self, value: &Value) -> EncodeResult<bool> {
+        if !self.use_references {
+            return Ok(false);
+        }
+
+        if let Some(index) = self.objects.iter().position(|v| v == value) {
+            try!(self.encode_u29((index as u32) << 1));
+            return Ok(true);
+        }
+
+        self.objects.push(value.clone());
+        Ok(false)
+    }
+
+    fn encode_utf8(&mut self, s: &str) -> EncodeResult<()> {
+        if self.use_references && !s.is_empty() {
+            if let Some(index) = self.strings.iter().position(|cached| cached == s) {
+                return self.encode_u29((index as u32) << 1);
+            }
+            self.strings.push(s.to_string());
+        }
+
+        self.encode_utf8_inline(s)
+    }
+
+    // Writes `s` as an inline U29-length-prefixed UTF-8 string, bypassing
+    // `encode_utf8`'s string-table dedup. `Xml`/`XmlDoc` content round-trips
+    // through the complex-value table (`self.objects`) via
+    // `try_write_value_reference`, the same as `Object`/`Array`/etc, rather
+    // than the plain-string table, so it must not also be pushed there.
+    fn encode_utf8_inline(&mut self, s: &str) -> EncodeResult<()> {
+        let size = ((s.len() << 1) | 0x01) as u32;
+        try!(self.encode_u29(size));
+
+        try!(self.writer.write_all(s.as_bytes()));
+        Ok(())
+    }
+
+    fn encode_pairs(&mut self, pairs: &[Pair<String, Value>]) -> EncodeResult<()> {
+        for pair in pairs {
+            try!(self.encode_utf8(&pair.key));
+            try!(self.encode_value(&pair.value));
+        }
+        try!(self.encode_utf8("")); // UTF-8-empty
+        Ok(())
+    }
+
+    fn encode_boolean(&mut self, boolean: bool) -> EncodeResult<()> {
+        if boolean {
+            try!(self.writer.write_u8(Marker::TRUE));
+        } else {
+            try!(self.writer.write_u8(Marker::FALSE));
+        }
+        Ok(())
+    }
+
+    fn encode_integer(&mut self, integer: i32) -> EncodeResult<()> {
+        // Integers outside the 29-bit signed range can't survive a round
+        // trip through `encode_u29` (it would silently wrap), so the spec
+        // has encoders fall back to the double marker for those.
+        if integer > MAX_29B_INT || integer < MIN_29B_INT {
+            return self.encode_double(integer as f64);
+        }
+        try!(self.writer.write_u8(Marker::INTEGER));
+        let u29 = if integer >= 0 {
+            integer as u32
+        } else {
+            ((1 << 29) + integer) as u32
+        };
+        try!(self.encode_u29(u29));
+        Ok(())
+    }
+
+    fn encode_double(&mut self, double: f64) -> EncodeResult<()> {
+        try!(self.writer.write_u8(Marker::DOUBLE));
+        try!(self.writer.write_f64::<BigEndian>(double));
+        Ok(())
+    }
+
+    fn encode_string(&mut self, string: &str) -> EncodeResult<()> {
+        try!(self.writer.write_u8(Marker::STRING));
+        try!(self.encode_utf8(string));
+        Ok(())
+    }
+
+    fn encode_xml_document(&mut self, value: &Value, xml_doc: &str) -> EncodeResult<()> {
+        try!(self.writer.write_u8(Marker::XML_DOC));
+        if try!(self.try_write_value_reference(value)) {
+            return Ok(());
+        }
+        try!(self.encode_utf8_inline(xml_doc));
+        Ok(())
+    }
+
+    fn encode_date(&mut self, unixtime: time::Duration) -> EncodeResult<()> {
+        let ms = unixtime.as_secs() * 1000 + (unixtime.subsec_nanos() as u64) / 1000_000;
+        try!(self.writer.write_u8(Marker::DATE));
+        let size = ((0 << 1) | 0x01) as u32;
+        try!(self.encode_u29(size));
+        try!(self.writer.write_f64::<BigEndian>(ms as f64));
+        Ok(())
+    }
+
+    fn encode_xml(&mut self, value: &Value, xml: &str) -> EncodeResult<()> {
+        try!(self.writer.write_u8(Marker::XML));
+        if try!(self.try_write_value_reference(value)) {
+            return Ok(());
+        }
+        try!(self.encode_utf8_inline(xml));
+        Ok(())
+    }
+
+    fn encode_object(
+        &mut self,
+        value: &Value,
+        name: &Option<String>,
+        sealed_count: usize,
+        pairs: &[Pair<String, Value>],
+    ) -> EncodeResult<()> {
+        try!(self.writer.write_u8(Marker::OBJECT));
+        if try!(self.try_write_value_reference(value)) {
+            return Ok(());
+        }
+
+        let is_dynamic = sealed_count < pairs.len();
+        let klass = Class {
+            name: name.clone(),
+            is_dynamic: is_dynamic,
+            is_externalizable: false,
+            fields: pairs.iter().take(sealed_count).map(|p| p.key.clone()).collect(),
+        };
+
+        if self.use_references {
+            if let Some(index) = self.traits.iter().position(|c| *c == klass) {
+                try!(self.encode_u29(((index as u32) << 2) | 0x1));
+                for pair in pairs.iter().take(sealed_count) {
+                    try!(self.encode_value(&pair.value));
+                }
+                if pairs.len() > sealed_count {
+                    try!(self.encode_pairs(&pairs[sealed_count..]));
+                }
+                return Ok(());
+            }
+            self.traits.push(klass);
+        }
+
+        let is_reference = 1 as usize;
+        let is_externalizable = false as usize;
+        let u29 = (sealed_count << 3) | ((is_dynamic as usize) << 2) | (is_externalizable << 1) | is_reference;
+        let size = ((u29 << 1) | 0x01) as u32;
+        try!(self.encode_u29(size));
+
+        let name = name.as_ref().map_or("", |s| &s);
+        try!(self.encode_utf8(name));
+        for pair in pairs.iter().take(sealed_count) {
+            try!(self.encode_utf8(&pair.key));
+        }
+
+        for pair in pairs.iter().take(sealed_count) {
+            try!(self.encode_value(&pair.value));
+        }
+
+        if pairs.len() > sealed_count {
+            try!(self.encode_pairs(&pairs[sealed_count..]));
+        }
+
+        Ok(())
+    }
+
+    fn encode_array(
+        &mut self,
+        value: &Value,
+        assoc: &[Pair<String, Value>],
+        dense: &[Value],
+    ) -> EncodeResult<()> {
+        try!(self.writer.write_u8(Marker::ARRAY));
+        if try!(self.try_write_value_reference(value)) {
+            return Ok(());
+        }
+        let size = ((dense.len() << 1) | 0x01) as u32;
+        try!(self.encode_u29(size));
+        try!(self.encode_pairs(assoc));
+        try!(
+            dense
+                .iter()
+                .map(|v| self.encode_value(v))
+                .collect::<EncodeResult<Vec<_>>>()
+        );
+        Ok(())
+    }
+
+    fn encode_byte_array(&mut self, value: &Value, bytes: &[u8]) -> EncodeResult<()> {
+        try!(self.writer.write_u8(Marker::BYTE_ARRAY));
+        if try!(self.try_write_value_reference(value)) {
+            return Ok(());
+        }
+        let size = ((bytes.len() << 1) | 0x01) as u32;
+        try!(self.encode_u29(size));
+        try!(self.writer.write_all(bytes));
+        Ok(())
+    }
+
+    fn encode_vector_int(&mut self, value: &Value, is_fixed: bool, vec: &[i32]) -> EncodeResult<()> {
+        try!(self.writer.write_u8(Marker::VECTOR_INT));
+        if try!(self.try_write_value_reference(value)) {
+            return Ok(());
+        }
+        let size = ((vec.len() << 1) | 0x01) as u32;
+        try!(self.encode_u29(size));
+        try!(self.writer.write_u8(is_fixed as u8));
+        for &v in vec {
+            try!(self.writer.write_i32::<BigEndian>(v));
+        }
+        Ok(())
+    }
+
+    fn encode_vector_uint(&mut self, value: &Value, is_fixed: bool, vec: &[u32]) -> EncodeResult<()> {
+        try!(self.writer.write_u8(Marker::VECTOR_UINT));
+        if try!(self.try_write_value_reference(value)) {
+            return Ok(());
+        }
+        let size = ((vec.len() << 1) | 0x01) as u32;
+        try!(self.encode_u29(size));
+        try!(self.writer.write_u8(is_fixed as u8));
+        for &v in vec {
+            try!(self.writer.write_u32::<BigEndian>(v));
+        }
+        Ok(())
+    }
+
+    fn encode_vector_double(&mut self, value: &Value, is_fixed: bool, vec: &[f64]) -> EncodeResult<()> {
+        try!(self.writer.write_u8(Marker::VECTOR_DOUBLE));
+        if try!(self.try_write_value_reference(value)) {
+            return Ok(());
+        }
+        let size = ((vec.len() << 1) | 0x01) as u32;
+        try!(self.encode_u29(size));
+        try!(self.writer.write_u8(is_fixed as u8));
+        for &v in vec {
+            try!(self.writer.write_f64::<BigEndian>(v));
+        }
+        Ok(())
+    }
+
+    fn encode_vector_object(
+        &mut self,
+        value: &Value,
+        name: &Option<String>,
+        is_fixed: bool,
+        vec: &[Value],
+    ) -> EncodeResult<()> {
+        try!(self.writer.write_u8(Marker::VECTOR_OBJECT));
+        if try!(self.try_write_value_reference(value)) {
+            return Ok(());
+        }
+        let size = ((vec.len() << 1) | 0x01) as u32;
+        try!(self.encode_u29(size));
+        try!(self.writer.write_u8(is_fixed as u8));
+        try!(self.encode_utf8(name.as_ref().map_or("*", |s| &s)));
+        for v in vec {
+            try!(self.encode_value(v));
+        }
+        Ok(())
+    }
+
+    fn encode_dictionary(
+        &mut self,
+        value: &Value,
+        is_weak: bool,
+        pairs: &[Pair<Value, Value>],
+    ) -> EncodeResult<()> {
+        try!(self.writer.write_u8(Marker::DICTIONARY));
+        if try!(self.try_write_value_reference(value)) {
+            return Ok(());
+        }
+        let size = ((pairs.len() << 1) | 0x01) as u32;
+        try!(self.encode_u29(size));
+        try!(self.writer.write_u8(is_weak as u8));
+        for pair in pairs {
+            try!(self.encode_value(&pair.key));
+            try!(self.encode_value(&pair.value));
+        }
+        Ok(())
+    }
+
+    fn encode_undefined(&mut self) -> EncodeResult<()> {
+        try!(self.writer.write_u8(Marker::UNDEFINED));
+        Ok(())
+    }
+
+    fn encode_null(&mut self) -> EncodeResult<()> {
+        try!(self.writer.write_u8(Marker::NULL));
+        Ok(())
+    }
+
+    fn encode_value(&mut self, value: &Value) -> EncodeResult<()> {
+        match *value {
+            Value::Undefined => self.encode_undefined(),
+            Value::Null => self.encode_null(),
+            Value::Boolean(boolean) => self.encode_boolean(boolean),
+            Value::Integer(integer) => self.encode_integer(integer),
+            Value::Double(double) => self.encode_double(double),
+            Value::String(ref string) => self.encode_string(string),
+            Value::XmlDoc(ref xml_doc) => self.encode_xml_document(value, xml_doc),
+            Value::Date { unixtime } => self.encode_date(unixtime),
+            Value::Object {
+                ref name,
+                sealed_count,
+                ref pairs,
+            } => self.encode_object(value, name, sealed_count, pairs),
+            Value::Xml(ref xml) => self.encode_xml(value, xml),
+            Value::Array {
+                ref assoc_entries,
+                ref dense_entries,
+            } => self.encode_array(value, assoc_entries, dense_entries),
+            Value::ByteArray(ref bytes) => self.encode_byte_array(value, bytes),
+            Value::IntVector {
+                is_fixed,
+                ref entries,
+            } => self.encode_vector_int(value, is_fixed, entries),
+            Value::UintVector {
+                is_fixed,
+                ref entries,
+            } => self.encode_vector_uint(value, is_fixed, entries),
+            Value::DoubleVector {
+                is_fixed,
+                ref entries,
+            } => self.encode_vector_double(value, is_fixed, entries),
+            Value::ObjectVector {
+                ref name,
+                is_fixed,
+                ref entries,
+            } => self.encode_vector_object(value, name, is_fixed, entries),
+            Value::Dictionary {
+                is_weak,
+                ref entries,
+            } => self.encode_dictionary(value, is_weak, entries),
+        }
+    }
+}
+
+/// Bridges `amf3::Value` onto serde's data model, mirroring `amf0::value_serde`:
+/// `Integer`/`Double` map to numbers, `String`/`Xml`/`XmlDoc` to `str`,
+/// `Object` to a map, `Array` to a seq (or a map when it carries only
+/// associative entries), `ByteArray` to serde bytes, and `Null`/`Undefined`
+/// to `none`/`unit`. `Dictionary` entries, whose keys are arbitrary `Value`s
+/// rather than strings, serialize as a seq of `(key, value)` pairs.
+#[cfg(feature = "serde")]
+pub mod value_serde {
+    use std::fmt;
+    use std::io;
+
+    use serde::{Serialize, Serializer, Deserialize, Deserializer};
+    use serde::ser::{self, SerializeMap, SerializeSeq};
+    use serde::de::{self, Visitor, SeqAccess, MapAccess};
+
+    use super::{Pair, Value, Decoder, Encoder, MIN_29B_INT, MAX_29B_INT};
+
+    #[derive(Debug)]
+    pub struct Error(String);
+
+    impl fmt::Display for Error {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "{}", self.0)
+        }
+    }
+
+    impl ::std::error::Error for Error {
+        fn description(&self) -> &str {
+            &self.0
+        }
+    }
+
+    impl ser::Error for Error {
+        fn custom<T: fmt::Display>(msg: T) -> Self {
+            Error(msg.to_string())
+        }
+    }
+
+    impl de::Error for Error {
+        fn custom<T: fmt::Display>(msg: T) -> Self {
+            Error(msg.to_string())
+        }
+    }
+
+    impl Serialize for Value {
+        fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+        {
+            match *self {
+                Value::Undefined => serializer.serialize_unit(),
+                Value::Null => serializer.serialize_none(),
+                Value::Boolean(b) => serializer.serialize_bool(b),
+                Value::Integer(n) => serializer.serialize_i32(n),
+                Value::Double(n) => serializer.serialize_f64(n),
+                Value::String(ref s) |
+                Value::XmlDoc(ref s) |
+                Value::Xml(ref s) => serializer.serialize_str(s),
+                Value::Date { unixtime } => {
+                    let ms = unixtime.as_secs() * 1000 +
+                        (unixtime.subsec_nanos() as u64) / 1_000_000;
+                    serializer.serialize_newtype_struct("Date", &ms)
+                }
+                Value::Object {
+                    name: _,
+                    sealed_count: _,
+                    ref pairs,
+                } => serialize_pairs(pairs, serializer),
+                Value::Array {
+                    ref assoc_entries,
+                    ref dense_entries,
+                } => {
+                    if dense_entries.is_empty() {
+                        serialize_pairs(assoc_entries, serializer)
+                    } else {
+                        let mut seq = try!(serializer.serialize_seq(Some(dense_entries.len())));
+                        for v in dense_entries {
+                            try!(seq.serialize_element(v));
+                        }
+                        seq.end()
+                    }
+                }
+                Value::ByteArray(ref bytes) => serializer.serialize_bytes(bytes),
+                Value::IntVector { ref entries, .. } => serialize_seq(entries, serializer),
+                Value::UintVector { ref entries, .. } => serialize_seq(entries, serializer),
+                Value::DoubleVector { ref entries, .. } => serialize_seq(entries, serializer),
+                Value::ObjectVector { ref entries, .. } => serialize_seq(entries, serializer),
+                Value::Dictionary { ref entries, .. } => {
+                    let mut seq = try!(serializer.serialize_seq(Some(entries.len())));
+                    for pair in entries {
+                        try!(seq.serialize_element(&(&pair.key, &pair.value)));
+                    }
+                    seq.end()
+                }
+            }
+        }
+    }
+
+    fn serialize_pairs<S>(pairs: &[Pair<String, Value>], serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut map = try!(serializer.serialize_map(Some(pairs.len())));
+        for pair in pairs {
+            try!(map.serialize_entry(&pair.key, &pair.value));
+        }
+        map.end()
+    }
+
+    fn serialize_seq<S, T>(entries: &[T], serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+        T: Serialize,
+    {
+        let mut seq = try!(serializer.serialize_seq(Some(entries.len())));
+        for v in entries {
+            try!(seq.serialize_element(v));
+        }
+        seq.end()
+    }
+
+    impl<'de> Deserialize<'de> for Value {
+        fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            deserializer.deserialize_any(ValueVisitor)
+        }
+    }
+
+    struct ValueVisitor;
+
+    impl<'de> Visitor<'de> for ValueVisitor {
+        type Value = Value;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a value representable as AMF3")
+        }
+
+        fn visit_bool<E>(self, v: bool) -> Result<Value, E> {
+            Ok(Value::Boolean(v))
+        }
+
+        fn visit_i64<E>(self, v: i64) -> Result<Value, E> {
+            if v >= MIN_29B_INT as i64 && v <= MAX_29B_INT as i64 {
+                Ok(Value::Integer(v as i32))
+            } else {
+                Ok(Value::Double(v as f64))
+            }
+        }
+
+        fn visit_u64<E>(self, v: u64) -> Result<Value, E> {
+            if v <= MAX_29B_INT as u64 {
+                Ok(Value::Integer(v as i32))
+            } else {
+                Ok(Value::Double(v as f64))
+            }
+        }
+
+        fn visit_f64<E>(self, v: f64) -> Result<Value, E> {
+            Ok(Value::Double(v))
+        }
+
+        fn visit_str<E>(self, v: &str) -> Result<Value, E>
+        where
+            E: de::Error,
+        {
+            Ok(Value::String(v.to_string()))
+        }
+
+        fn visit_string<E>(self, v: String) -> Result<Value, E> {
+            Ok(Value::String(v))
+        }
+
+        fn visit_bytes<E>(self, v: &[u8]) -> Result<Value, E> {
+            Ok(Value::ByteArray(v.to_vec()))
+        }
+
+        fn visit_byte_buf<E>(self, v: Vec<u8>) -> Result<Value, E> {
+            Ok(Value::ByteArray(v))
+        }
+
+        fn visit_unit<E>(self) -> Result<Value, E> {
+            Ok(Value::Undefined)
+        }
+
+        fn visit_none<E>(self) -> Result<Value, E> {
+            Ok(Value::Null)
+        }
+
+        fn visit_some<D>(self, deserializer: D) -> Result<Value, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            Deserialize::deserialize(deserializer)
+        }
+
+        fn visit_seq<A>(self, mut seq: A) -> Result<Value, A::Error>
+        where
+            A: SeqAccess<'de>,
+        {
+            let mut values = Vec::new();
+            while let Some(v) = try!(seq.next_element()) {
+                values.push(v);
+            }
+            Ok(Value::Array {
+                assoc_entries: Vec::new(),
+                dense_entries: values,
+            })
+        }
+
+        fn visit_map<A>(self, mut map: A) -> Result<Value, A::Error>
+        where
+            A: MapAccess<'de>,
+        {
+            let mut pairs = Vec::new();
+            while let Some((key, value)) = try!(map.next_entry::<String, Value>()) {
+                pairs.push(Pair {
+                    key: key,
+                    value: value,
+                });
+            }
+            Ok(Value::Object {
+                name: None,
+                sealed_count: 0,
+                pairs: pairs,
+            })
+        }
+    }
+
+    /// Converts any `T: Serialize` into a `Value`, analogous to `serde_json::to_value`.
+    pub fn to_value<T>(value: T) -> Result<Value, Error>
+    where
+        T: Serialize,
+    {
+        value.serialize(ValueToValueSerializer)
+    }
+
+    /// Converts a decoded `Value` back into any `T: Deserialize`.
+    pub fn from_value<T>(value: Value) -> Result<T, Error>
+    where
+        T: for<'de> Deserialize<'de>,
+    {
+        T::deserialize(value)
+    }
+
+    /// Serializes any `T: Serialize` straight to AMF3 bytes, analogous to
+    /// `serde_json::to_writer`.
+    pub fn to_writer<W, T>(writer: W, value: &T) -> Result<(), Error>
+    where
+        W: io::Write,
+        T: Serialize,
+    {
+        let value = try!(value.serialize(ValueToValueSerializer));
+        Encoder::new(writer).encode(&value).map_err(
+            |e| Error(e.to_string()),
+        )
+    }
+
+    /// Decodes a single AMF3 value from `reader` and converts it into any
+    /// `T: Deserialize`, analogous to `serde_json::from_reader`.
+    pub fn from_reader<R, T>(reader: R) -> Result<T, Error>
+    where
+        R: io::Read,
+        T: for<'de> Deserialize<'de>,
+    {
+        let value = try!(Decoder::new(reader).decode().map_err(
+            |e| Error(e.to_string()),
+        ));
+        from_value(value)
+    }
+
+    /// Serializes any `T: Serialize` straight to an AMF3-encoded `Vec<u8>`,
+    /// analogous to `serde_json::to_vec`.
+    pub fn to_bytes<T>(value: &T) -> Result<Vec<u8>, Error>
+    where
+        T: Serialize,
+    {
+        let mut buf = Vec::new();
+        try!(to_writer(&mut buf, value));
+        Ok(buf)
+    }
+
+    /// Decodes a single AMF3 value from `bytes` and converts it into any
+    /// `T: Deserialize`, analogous to `serde_json::from_slice`.
+    pub fn from_bytes<T>(bytes: &[u8]) -> Result<T, Error>
+    where
+        T: for<'de> Deserialize<'de>,
+    {
+        from_reader(bytes)
+    }
+
+    impl<'de> Deserializer<'de> for Value {
+        type Error = Error;
+
+        fn deserialize_any<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            match self {
+                Value::Undefined => visitor.visit_unit(),
+                Value::Null => visitor.visit_none(),
+                Value::Boolean(b) => visitor.visit_bool(b),
+                Value::Integer(n) => visitor.visit_i64(n as i64),
+                Value::Double(n) => visitor.visit_f64(n),
+                Value::String(s) |
+                Value::XmlDoc(s) |
+                Value::Xml(s) => visitor.visit_string(s),
+                Value::Date { unixtime } => {
+                    let ms = unixtime.as_secs() * 1000 +
+                        (unixtime.subsec_nanos() as u64) / 1_000_000;
+                    visitor.visit_u64(ms)
+                }
+                Value::Object { pairs, .. } => {
+                    visitor.visit_map(PairsDeserializer {
+                        iter: pairs.into_iter(),
+                        value: None,
+                    })
+                }
+                Value::Array {
+                    assoc_entries,
+                    dense_entries,
+                } => {
+                    if dense_entries.is_empty() {
+                        visitor.visit_map(PairsDeserializer {
+                            iter: assoc_entries.into_iter(),
+                            value: None,
+                        })
+                    } else {
+                        visitor.visit_seq(SeqDeserializer { iter: dense_entries.into_iter() })
+                    }
+                }
+                Value::ByteArray(bytes) => visitor.visit_byte_buf(bytes),
+                Value::IntVector { entries, .. } => {
+                    visitor.visit_seq(I32SeqDeserializer { iter: entries.into_iter() })
+                }
+                Value::UintVector { .. } |
+                Value::DoubleVector { .. } |
+                Value::ObjectVector { .. } |
+                Value::Dictionary { .. } => {
+                    Err(Error(
+                        "this AMF3 collection kind is not supported by the serde bridge"
+                            .to_string(),
+                    ))
+                }
+            }
+        }
+
+        fn deserialize_option<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            match self {
+                Value::Null => visitor.visit_none(),
+                other => visitor.visit_some(other),
+            }
+        }
+
+        fn deserialize_bool<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_i8<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_i16<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_i32<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_i64<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_u8<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_u16<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_u32<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_u64<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_f32<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_f64<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_char<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_str<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_string<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_bytes<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_byte_buf<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_unit<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_unit_struct<V>(
+            self,
+            _name: &'static str,
+            visitor: V,
+        ) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_newtype_struct<V>(
+            self,
+            _name: &'static str,
+            visitor: V,
+        ) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            visitor.visit_newtype_struct(self)
+        }
+        fn deserialize_seq<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_tuple<V>(self, _len: usize, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_tuple_struct<V>(
+            self,
+            _name: &'static str,
+            _len: usize,
+            visitor: V,
+        ) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_map<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_struct<V>(
+            self,
+            _name: &'static str,
+            _fields: &'static [&'static str],
+            visitor: V,
+        ) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_enum<V>(
+            self,
+            _name: &'static str,
+            _variants: &'static [&'static str],
+            visitor: V,
+        ) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            match self {
+                Value::String(variant) => {
+                    visitor.visit_enum(EnumDeserializer { variant: variant, value: None })
+                }
+                Value::Object { pairs, .. } => {
+                    let mut iter = pairs.into_iter();
+                    let pair = match iter.next() {
+                        Some(pair) => pair,
+                        None => {
+                            return Err(Error(
+                                "expected a single-entry map representing an enum variant"
+                                    .to_string(),
+                            ))
+                        }
+                    };
+                    visitor.visit_enum(EnumDeserializer { variant: pair.key, value: Some(pair.value) })
+                }
+                other => Err(Error(format!("invalid type: {:?}, expected enum", other))),
+            }
+        }
+        fn deserialize_identifier<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+        fn deserialize_ignored_any<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            self.deserialize_any(visitor)
+        }
+    }
+
+    // Drives `Visitor::visit_enum` off the single-entry `Object` (or bare
+    // `String` for a unit variant) that `serialize_*_variant` below produces,
+    // so enums tagged that way can round-trip back through `from_value`.
+    struct EnumDeserializer {
+        variant: String,
+        value: Option<Value>,
+    }
+
+    impl<'de> de::EnumAccess<'de> for EnumDeserializer {
+        type Error = Error;
+        type Variant = VariantDeserializer;
+
+        fn variant_seed<V>(self, seed: V) -> Result<(V::Value, VariantDeserializer), Error>
+        where
+            V: de::DeserializeSeed<'de>,
+        {
+            let variant = try!(seed.deserialize(Value::String(self.variant)));
+            Ok((variant, VariantDeserializer { value: self.value }))
+        }
+    }
+
+    struct VariantDeserializer {
+        value: Option<Value>,
+    }
+
+    impl<'de> de::VariantAccess<'de> for VariantDeserializer {
+        type Error = Error;
+
+        fn unit_variant(self) -> Result<(), Error> {
+            match self.value {
+                None => Ok(()),
+                Some(value) => Err(Error(format!("invalid type: {:?}, expected unit variant", value))),
+            }
+        }
+
+        fn newtype_variant_seed<T>(self, seed: T) -> Result<T::Value, Error>
+        where
+            T: de::DeserializeSeed<'de>,
+        {
+            match self.value {
+                Some(value) => seed.deserialize(value),
+                None => Err(Error("expected a newtype variant, found a unit variant".to_string())),
+            }
+        }
+
+        fn tuple_variant<V>(self, _len: usize, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            match self.value {
+                Some(Value::Array { dense_entries, .. }) => {
+                    visitor.visit_seq(SeqDeserializer { iter: dense_entries.into_iter() })
+                }
+                Some(value) => Err(Error(format!("invalid type: {:?}, expected tuple variant", value))),
+                None => Err(Error("expected a tuple variant, found a unit variant".to_string())),
+            }
+        }
+
+        fn struct_variant<V>(
+            self,
+            _fields: &'static [&'static str],
+            visitor: V,
+        ) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            match self.value {
+                Some(Value::Object { pairs, .. }) => {
+                    visitor.visit_map(PairsDeserializer { iter: pairs.into_iter(), value: None })
+                }
+                Some(value) => Err(Error(format!("invalid type: {:?}, expected struct variant", value))),
+                None => Err(Error("expected a struct variant, found a unit variant".to_string())),
+            }
+        }
+    }
+
+    struct SeqDeserializer {
+        iter: ::std::vec::IntoIter<Value>,
+    }
+
+    impl<'de> SeqAccess<'de> for SeqDeserializer {
+        type Error = Error;
+
+        fn next_element_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>, Error>
+        where
+            T: de::DeserializeSeed<'de>,
+        {
+            match self.iter.next() {
+                Some(v) => seed.deserialize(v).map(Some),
+                None => Ok(None),
+            }
+        }
+    }
+
+    struct I32SeqDeserializer {
+        iter: ::std::vec::IntoIter<i32>,
+    }
+
+    impl<'de> SeqAccess<'de> for I32SeqDeserializer {
+        type Error = Error;
+
+        fn next_element_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>, Error>
+        where
+            T: de::DeserializeSeed<'de>,
+        {
+            match self.iter.next() {
+                Some(v) => seed.deserialize(Value::Integer(v)).map(Some),
+                None => Ok(None),
+            }
+        }
+    }
+
+    struct PairsDeserializer {
+        iter: ::std::vec::IntoIter<Pair<String, Value>>,
+        value: Option<Value>,
+    }
+
+    impl<'de> MapAccess<'de> for PairsDeserializer {
+        type Error = Error;
+
+        fn next_key_seed<K>(&mut self, seed: K) -> Result<Option<K::Value>, Error>
+        where
+            K: de::DeserializeSeed<'de>,
+        {
+            match self.iter.next() {
+                Some(pair) => {
+                    self.value = Some(pair.value);
+                    seed.deserialize(Value::String(pair.key)).map(Some)
+                }
+                None => Ok(None),
+            }
+        }
+
+        fn next_value_seed<V>(&mut self, seed: V) -> Result<V::Value, Error>
+        where
+            V: de::DeserializeSeed<'de>,
+        {
+            let value = self.value.take().expect(
+                "next_value_seed called before next_key_seed",
+            );
+            seed.deserialize(value)
+        }
+    }
+
+    // A minimal `Serializer` whose every method builds a `Value`, mirroring
+    // `amf0::value_serde`'s `ValueToValueSerializer`.
+    struct ValueToValueSerializer;
+
+    impl Serializer for ValueToValueSerializer {
+        type Ok = Value;
+        type Error = Error;
+        type SerializeSeq = SeqBuilder;
+        type SerializeTuple = SeqBuilder;
+        type SerializeTupleStruct = SeqBuilder;
+        type SerializeTupleVariant = SeqBuilder;
+        type SerializeMap = MapBuilder;
+        type SerializeStruct = MapBuilder;
+        type SerializeStructVariant = MapBuilder;
+
+        fn serialize_bool(self, v: bool) -> Result<Value, Error> {
+            Ok(Value::Boolean(v))
+        }
+        fn serialize_i8(self, v: i8) -> Result<Value, Error> {
+            Ok(Value::Integer(v as i32))
+        }
+        fn serialize_i16(self, v: i16) -> Result<Value, Error> {
+            Ok(Value::Integer(v as i32))
+        }
+        fn serialize_i32(self, v: i32) -> Result<Value, Error> {
+            Ok(Value::Integer(v))
+        }
+        fn serialize_i64(self, v: i64) -> Result<Value, Error> {
+            if v >= MIN_29B_INT as i64 && v <= MAX_29B_INT as i64 {
+                Ok(Value::Integer(v as i32))
+            } else {
+                Ok(Value::Double(v as f64))
+            }
+        }
+        fn serialize_u8(self, v: u8) -> Result<Value, Error> {
+            Ok(Value::Integer(v as i32))
+        }
+        fn serialize_u16(self, v: u16) -> Result<Value, Error> {
+            Ok(Value::Integer(v as i32))
+        }
+        fn serialize_u32(self, v: u32) -> Result<Value, Error> {
+            if v <= MAX_29B_INT as u32 {
+                Ok(Value::Integer(v as i32))
+            } else {
+                Ok(Value::Double(v as f64))
+            }
+        }
+        fn serialize_u64(self, v: u64) -> Result<Value, Error> {
+            if v <= MAX_29B_INT as u64 {
+                Ok(Value::Integer(v as i32))
+            } else {
+                Ok(Value::Double(v as f64))
+            }
+        }
+        fn serialize_f32(self, v: f32) -> Result<Value, Error> {
+            Ok(Value::Double(v as f64))
+        }
+        fn serialize_f64(self, v: f64) -> Result<Value, Error> {
+            Ok(Value::Double(v))
+        }
+        fn serialize_char(self, v: char) -> Result<Value, Error> {
+            Ok(Value::String(v.to_string()))
+        }
+        fn serialize_str(self, v: &str) -> Result<Value, Error> {
+            Ok(Value::String(v.to_string()))
+        }
+        fn serialize_bytes(self, v: &[u8]) -> Result<Value, Error> {
+            Ok(Value::ByteArray(v.to_vec()))
+        }
+        fn serialize_none(self) -> Result<Value, Error> {
+            Ok(Value::Null)
+        }
+        fn serialize_some<T: ?Sized>(self, value: &T) -> Result<Value, Error>
+        where
+            T: Serialize,
+        {
+            value.serialize(self)
+        }
+        fn serialize_unit(self) -> Result<Value, Error> {
+            Ok(Value::Undefined)
+        }
+        fn serialize_unit_struct(self, _name: &'static str) -> Result<Value, Error> {
+            Ok(Value::Undefined)
+        }
+        fn serialize_unit_variant(
+            self,
+            _name: &'static str,
+            _index: u32,
+            variant: &'static str,
+        ) -> Result<Value, Error> {
+            Ok(Value::String(variant.to_string()))
+        }
+        fn serialize_newtype_struct<T: ?Sized>(
+            self,
+            _name: &'static str,
+            value: &T,
+        ) -> Result<Value, Error>
+        where
+            T: Serialize,
+        {
+            value.serialize(self)
+        }
+        fn serialize_newtype_variant<T: ?Sized>(
+            self,
+            _name: &'static str,
+            _index: u32,
+            variant: &'static str,
+            value: &T,
+        ) -> Result<Value, Error>
+        where
+            T: Serialize,
+        {
+            Ok(Value::Object {
+                name: None,
+                sealed_count: 0,
+                pairs: vec![
+                    Pair {
+                        key: variant.to_string(),
+                        value: try!(value.serialize(ValueToValueSerializer)),
+                    },
+                ],
+            })
+        }
+        fn serialize_seq(self, len: Option<usize>) -> Result<SeqBuilder, Error> {
+            Ok(SeqBuilder {
+                values: Vec::with_capacity(len.unwrap_or(0)),
+                variant: None,
+            })
+        }
+        fn serialize_tuple(self, len: usize) -> Result<SeqBuilder, Error> {
+            self.serialize_seq(Some(len))
+        }
+        fn serialize_tuple_struct(
+            self,
+            _name: &'static str,
+            len: usize,
+        ) -> Result<SeqBuilder, Error> {
+            self.serialize_seq(Some(len))
+        }
+        fn serialize_tuple_variant(
+            self,
+            _name: &'static str,
+            _index: u32,
+            variant: &'static str,
+            len: usize,
+        ) -> Result<SeqBuilder, Error> {
+            Ok(SeqBuilder {
+                values: Vec::with_capacity(len),
+                variant: Some(variant),
+            })
+        }
+        fn serialize_map(self, len: Option<usize>) -> Result<MapBuilder, Error> {
+            Ok(MapBuilder {
+                pairs: Vec::with_capacity(len.unwrap_or(0)),
+                next_key: None,
+                variant: None,
+            })
+        }
+        fn serialize_struct(
+            self,
+            _name: &'static str,
+            len: usize,
+        ) -> Result<MapBuilder, Error> {
+            self.serialize_map(Some(len))
+        }
+        fn serialize_struct_variant(
+            self,
+            _name: &'static str,
+            _index: u32,
+            variant: &'static str,
+            len: usize,
+        ) -> Result<MapBuilder, Error> {
+            Ok(MapBuilder {
+                pairs: Vec::with_capacity(len),
+                next_key: None,
+                variant: Some(variant),
+            })
+        }
+    }
+
+    struct SeqBuilder {
+        values: Vec<Value>,
+        variant: Option<&'static str>,
+    }
+
+    impl SeqBuilder {
+        fn into_value(self) -> Value {
+            let array = Value::Array {
+                assoc_entries: Vec::new(),
+                dense_entries: self.values,
+            };
+            match self.variant {
+                Some(variant) => {
+                    Value::Object {
+                        name: None,
+                        sealed_count: 0,
+                        pairs: vec![
+                            Pair {
+                                key: variant.to_string(),
+                                value: array,
+                            },
+                        ],
+                    }
+                }
+                None => array,
+            }
+        }
+    }
+
+    impl ser::SerializeSeq for SeqBuilder {
+        type Ok = Value;
+        type Error = Error;
+        fn serialize_element<T: ?Sized>(&mut self, value: &T) -> Result<(), Error>
+        where
+            T: Serialize,
+        {
+            self.values.push(try!(value.serialize(ValueToValueSerializer)));
+            Ok(())
+        }
+        fn end(self) -> Result<Value, Error> {
+            Ok(self.into_value())
+        }
+    }
+
+    impl ser::SerializeTuple for SeqBuilder {
+        type Ok = Value;
+        type Error = Error;
+        fn serialize_element<T: ?Sized>(&mut self, value: &T) -> Result<(), Error>
+        where
+            T: Serialize,
+        {
+            ser::SerializeSeq::serialize_element(self, value)
+        }
+        fn end(self) -> Result<Value, Error> {
+            ser::SerializeSeq::end(self)
+        }
+    }
+
+    impl ser::SerializeTupleStruct for SeqBuilder {
+        type Ok = Value;
+        type Error = Error;
+        fn serialize_field<T: ?Sized>(&mut self, value: &T) -> Result<(), Error>
+        where
+            T: Serialize,
+        {
+            ser::SerializeSeq::serialize_element(self, value)
+        }
+        fn end(self) -> Result<Value, Error> {
+            ser::SerializeSeq::end(self)
+        }
+    }
+
+    impl ser::SerializeTupleVariant for SeqBuilder {
+        type Ok = Value;
+        type Error = Error;
+        fn serialize_field<T: ?Sized>(&mut self, value: &T) -> Result<(), Error>
+        where
+            T: Serialize,
+        {
+            ser::SerializeSeq::serialize_element(self, value)
+        }
+        fn end(self) -> Result<Value, Error> {
+            ser::SerializeSeq::end(self)
+        }
+    }
+
+    struct MapBuilder {
+        pairs: Vec<Pair<String, Value>>,
+        next_key: Option<String>,
+        variant: Option<&'static str>,
+    }
+
+    impl MapBuilder {
+        fn into_value(self) -> Value {
+            let object = Value::Object {
+                name: None,
+                sealed_count: 0,
+                pairs: self.pairs,
+            };
+            match self.variant {
+                Some(variant) => {
+                    Value::Object {
+                        name: None,
+                        sealed_count: 0,
+                        pairs: vec![
+                            Pair {
+                                key: variant.to_string(),
+                                value: object,
+                            },
+                        ],
+                    }
+                }
+                None => object,
+            }
         }
-        Ok(())
     }
 
-    fn encode_undefined(&mut self) -> EncodeResult<()> {
-        try!(self.writer.write_u8(Marker::UNDEFINED));
-        Ok(())
+    impl ser::SerializeMap for MapBuilder {
+        type Ok = Value;
+        type Error = Error;
+        fn serialize_key<T: ?Sized>(&mut self, key: &T) -> Result<(), Error>
+        where
+            T: Serialize,
+        {
+            let key = try!(key.serialize(ValueToValueSerializer));
+            self.next_key = Some(match key {
+                Value::String(s) => s,
+                other => return Err(Error(format!("non-string map key: {:?}", other))),
+            });
+            Ok(())
+        }
+        fn serialize_value<T: ?Sized>(&mut self, value: &T) -> Result<(), Error>
+        where
+            T: Serialize,
+        {
+            let key = self.next_key.take().unwrap_or_default();
+            self.pairs.push(Pair {
+                key: key,
+                value: try!(value.serialize(ValueToValueSerializer)),
+            });
+            Ok(())
+        }
+        fn end(self) -> Result<Value, Error> {
+            Ok(self.into_value())
+        }
     }
 
-    fn encode_null(&mut self) -> EncodeResult<()> {
-        try!(self.writer.write_u8(Marker::NULL));
-        Ok(())
+    impl ser::SerializeStruct for MapBuilder {
+        type Ok = Value;
+        type Error = Error;
+        fn serialize_field<T: ?Sized>(
+            &mut self,
+            key: &'static str,
+            value: &T,
+        ) -> Result<(), Error>
+        where
+            T: Serialize,
+        {
+            self.pairs.push(Pair {
+                key: key.to_string(),
+                value: try!(value.serialize(ValueToValueSerializer)),
+            });
+            Ok(())
+        }
+        fn end(self) -> Result<Value, Error> {
+            Ok(self.into_value())
+        }
     }
 
-    fn encode_value(&mut self, value: &Value) -> EncodeResult<()> {
-        match *value {
-            Value::Undefined => self.encode_undefined(),
-            Value::Null => self.encode_null(),
-            Value::Boolean(boolean) => self.encode_boolean(boolean),
-            Value::Integer(integer) => self.encode_integer(integer),
-            Value::Double(double) => self.encode_double(double),
-            Value::String(ref string) => self.encode_string(string),
-            Value::XmlDoc(ref xml_doc) => self.encode_xml_document(xml_doc),
-            Value::Date { unixtime } => self.encode_date(unixtime),
-            Value::Object {
-                ref name,
-                sealed_count,
-                ref pairs,
-            } => self.encode_object(name, sealed_count, pairs),
-            Value::Xml(ref xml) => self.encode_xml(xml),
-            Value::Array {
-                ref assoc_entries,
-                ref dense_entries,
-            } => self.encode_array(assoc_entries, dense_entries),
-            Value::ByteArray(ref bytes) => self.encode_byte_array(bytes),
-            Value::IntVector {
-                is_fixed,
-                ref entries,
-            } => self.encode_vector_int(is_fixed, entries),
-            Value::UintVector {
-                is_fixed,
-                ref entries,
-            } => self.encode_vector_uint(is_fixed, entries),
-            Value::DoubleVector {
-                is_fixed,
-                ref entries,
-            } => self.encode_vector_double(is_fixed, entries),
-            Value::ObjectVector {
-                ref name,
-                is_fixed,
-                ref entries,
-            } => self.encode_vector_object(name, is_fixed, entries),
-            Value::Dictionary {
-                is_weak,
-                ref entries,
-            } => self.encode_dictionary(is_weak, entries),
+    impl ser::SerializeStructVariant for MapBuilder {
+        type Ok = Value;
+        type Error = Error;
+        fn serialize_field<T: ?Sized>(
+            &mut self,
+            key: &'static str,
+            value: &T,
+        ) -> Result<(), Error>
+        where
+            T: Serialize,
+        {
+            ser::SerializeStruct::serialize_field(self, key, value)
+        }
+        fn end(self) -> Result<Value, Error> {
+            ser::SerializeStruct::end(self)
         }
     }
 }
@@ -889,6 +3296,7 @@ where
 #[cfg(test)]
 mod test {
     use std::fs;
+    use std::io;
     use std::io::BufReader;
     use std::time;
 
@@ -896,6 +3304,8 @@ mod test {
     use super::Decoder;
     use super::Pair;
     use super::Encoder;
+    use super::Marker;
+    use super::Externalizable;
 
     macro_rules! macro_decode {
         ($sample_file: expr) => {
@@ -1416,6 +3826,114 @@ mod test {
         assert_eq!(value, result);
     }
 
+    #[test]
+    fn encode_with_references() {
+        let shared = Value::Object {
+            name: None,
+            sealed_count: 1,
+            pairs: vec![
+                Pair {
+                    key: "msg".to_string(),
+                    value: Value::String("Hello, world!".to_string()),
+                },
+            ],
+        };
+        let value = Value::Array {
+            assoc_entries: vec![],
+            dense_entries: vec![shared.clone(), shared.clone()],
+        };
+
+        let mut buf = Vec::new();
+        Encoder::new(&mut buf).with_references().encode(&value).unwrap();
+
+        let mut decoder = Decoder::new(&buf[..]);
+        let result = decoder.decode().unwrap();
+        assert_eq!(value, result);
+
+        // Without references enabled, the same value round-trips but produces
+        // a larger, fully-inlined encoding.
+        let mut buf_inline = Vec::new();
+        Encoder::new(&mut buf_inline).encode(&value).unwrap();
+        assert!(buf_inline.len() > buf.len());
+    }
+
+    #[test]
+    fn encode_with_references_dedupes_traits_across_distinct_instances() {
+        // Two distinct (differently-valued) instances of the same typed
+        // class: the objects themselves can't be deduped via the object
+        // table, but the shared trait (name + sealed field names) should
+        // still be written once and referenced the second time.
+        let make = |msg: &str| {
+            Value::Object {
+                name: Some("com.pyyoshi.hoge".to_string()),
+                sealed_count: 1,
+                pairs: vec![
+                    Pair {
+                        key: "msg".to_string(),
+                        value: Value::String(msg.to_string()),
+                    },
+                ],
+            }
+        };
+        let value = Value::Array {
+            assoc_entries: vec![],
+            dense_entries: vec![make("one"), make("two")],
+        };
+
+        let mut buf = Vec::new();
+        Encoder::new(&mut buf).with_references().encode(&value).unwrap();
+
+        let mut decoder = Decoder::new(&buf[..]);
+        let result = decoder.decode().unwrap();
+        assert_eq!(value, result);
+
+        let mut buf_inline = Vec::new();
+        Encoder::new(&mut buf_inline).encode(&value).unwrap();
+        assert!(buf_inline.len() > buf.len());
+    }
+
+    #[test]
+    fn encode_with_references_round_trips_duplicate_xml_and_xml_doc_values() {
+        let value = Value::Array {
+            assoc_entries: vec![],
+            dense_entries: vec![
+                Value::Xml("<a/>".to_string()),
+                Value::Xml("<a/>".to_string()),
+                Value::XmlDoc("<b/>".to_string()),
+                Value::XmlDoc("<b/>".to_string()),
+            ],
+        };
+
+        let mut buf = Vec::new();
+        Encoder::new(&mut buf).with_references().encode(&value).unwrap();
+
+        let mut decoder = Decoder::new(&buf[..]);
+        let result = decoder.decode().unwrap();
+        assert_eq!(value, result);
+    }
+
+    #[test]
+    fn encode_with_references_does_not_confuse_xml_with_an_identical_string() {
+        // `Xml` content is deduped against the complex-value table
+        // (`self.objects`), not the plain-string table, so an `Xml` value
+        // followed by a `String` with the same text must not be mistaken
+        // for a reference to one another.
+        let value = Value::Array {
+            assoc_entries: vec![],
+            dense_entries: vec![
+                Value::Xml("shared".to_string()),
+                Value::String("shared".to_string()),
+            ],
+        };
+
+        let mut buf = Vec::new();
+        Encoder::new(&mut buf).with_references().encode(&value).unwrap();
+
+        let mut decoder = Decoder::new(&buf[..]);
+        let result = decoder.decode().unwrap();
+        assert_eq!(value, result);
+    }
+
     #[test]
     fn encode_dictionary() {
         let value = Value::Dictionary {
@@ -1437,4 +3955,441 @@ mod test {
         };
         macro_encode_equal!(value, "amf3-dictionary.bin");
     }
+
+    #[test]
+    fn decode_object_registers_itself_in_the_reference_table() {
+        // An AMF3 array holding the same dynamic, fieldless, unnamed object
+        // twice: the first occurrence is written inline (object-ref bit set,
+        // trait-inline bit set, is_dynamic bit set, 0 sealed fields), the
+        // second is a bare reference back to its object-table slot. The
+        // array itself occupies object-table index 0, so the object lands
+        // at index 1.
+        let buf: Vec<u8> = vec![
+            Marker::ARRAY,
+            0x05, // u29 = 5: array-ref=1, dense count=2
+            0x01, // associative entries terminator: empty inline string
+            Marker::OBJECT,
+            0x0B, // u29 = 11: object-ref=1, trait-inline=1, is_dynamic=1, field_num=0
+            0x01, // class name: empty inline string
+            0x01, // dynamic members terminator: empty inline string
+            Marker::OBJECT,
+            0x02, // u29 = 2: object reference to index 1 (index 0 is the array itself)
+        ];
+
+        let mut decoder = Decoder::new(&buf[..]);
+        let value = decoder.decode().unwrap();
+        let expected_object = Value::Object {
+            name: None,
+            sealed_count: 0,
+            pairs: vec![],
+        };
+        assert_eq!(
+            value,
+            Value::Array {
+                assoc_entries: vec![],
+                dense_entries: vec![expected_object.clone(), expected_object],
+            }
+        );
+    }
+
+    #[test]
+    fn decode_consults_the_externalizable_registry() {
+        // Stands in for a Flex `IExternalizable` class (e.g.
+        // `flex.messaging.io.ArrayCollection`) whose `writeExternal` just
+        // writes one ordinary AMF3 value.
+        struct StubExternalizable;
+        impl Externalizable for StubExternalizable {
+            fn read_external<R: io::Read>(decoder: &mut Decoder<R>) -> super::DecodeResult<Value> {
+                decoder.decode_value()
+            }
+        }
+
+        // OBJECT, u29=7 (object-ref=1, trait-inline=1, externalizable=1),
+        // class name "Foo" inline, then one externalized Integer(42).
+        let buf: Vec<u8> = vec![
+            Marker::OBJECT,
+            0x07,
+            0x07,
+            b'F',
+            b'o',
+            b'o',
+            Marker::INTEGER,
+            0x2A,
+        ];
+
+        let mut decoder = Decoder::new(&buf[..]).register_externalizable::<StubExternalizable>("Foo");
+        assert_eq!(decoder.decode().unwrap(), Value::Integer(42));
+    }
+
+    #[test]
+    fn decode_reports_unregistered_externalizable_classes() {
+        let buf: Vec<u8> = vec![
+            Marker::OBJECT,
+            0x07,
+            0x07,
+            b'B',
+            b'a',
+            b'r',
+        ];
+
+        let err = Decoder::new(&buf[..]).decode().unwrap_err();
+        assert_eq!(
+            err.kind,
+            super::DecodeErrorKind::ExternalizableType { name: "Bar".to_string() }
+        );
+    }
+
+    #[test]
+    fn decode_builtin_flex_wrapper_unwraps_nested_value() {
+        // OBJECT, u29=7 (object-ref=1, trait-inline=1, externalizable=1),
+        // class name "flex.messaging.io.ArrayCollection" inline, then one
+        // externalized Integer(42) standing in for the wrapped value.
+        let mut buf: Vec<u8> = vec![Marker::OBJECT, 0x07, 0x43];
+        buf.extend_from_slice(b"flex.messaging.io.ArrayCollection");
+        buf.push(Marker::INTEGER);
+        buf.push(0x2A);
+
+        let mut decoder = Decoder::new(&buf[..]);
+        assert_eq!(decoder.decode().unwrap(), Value::Integer(42));
+    }
+
+    #[test]
+    fn register_external_overrides_handler_with_a_closure() {
+        let buf: Vec<u8> = vec![
+            Marker::OBJECT,
+            0x07,
+            0x07,
+            b'F',
+            b'o',
+            b'o',
+            Marker::INTEGER,
+            0x2A,
+        ];
+
+        let mut decoder = Decoder::new(&buf[..]);
+        decoder.register_external("Foo", |d| d.decode_next().map(|_| Value::Undefined));
+        assert_eq!(decoder.decode().unwrap(), Value::Undefined);
+    }
+
+    #[test]
+    fn decode_rejects_nesting_beyond_the_configured_max_depth() {
+        let value = Value::Array {
+            assoc_entries: vec![],
+            dense_entries: vec![
+                Value::Array {
+                    assoc_entries: vec![],
+                    dense_entries: vec![Value::Null],
+                },
+            ],
+        };
+
+        let mut buf = Vec::new();
+        Encoder::new(&mut buf).encode(&value).unwrap();
+
+        let mut decoder = Decoder::new(&buf[..]).with_max_depth(1);
+        let err = decoder.decode().unwrap_err();
+        assert_eq!(err.kind, super::DecodeErrorKind::DepthLimitExceeded { limit: 1 });
+        assert_eq!(err.path.as_ref().map(String::as_str), Some("$[0]"));
+
+        // Without the guard the same bytes decode fine.
+        let mut unguarded = Decoder::new(&buf[..]);
+        assert_eq!(unguarded.decode().unwrap(), value);
+    }
+
+    #[test]
+    fn decode_rejects_collection_counts_beyond_the_configured_max_len() {
+        // ARRAY, u29=0x1FFFFFFF (object-ref=1, forged dense count=0x0FFFFFFF).
+        let buf: Vec<u8> = vec![Marker::ARRAY, 0xFF, 0xFF, 0xFF, 0xFF];
+
+        let mut decoder = Decoder::new(&buf[..]).with_max_collection_len(1000);
+        let err = decoder.decode().unwrap_err();
+        assert_eq!(err.kind, super::DecodeErrorKind::CollectionTooLarge { len: 0x0FFF_FFFF });
+    }
+
+    #[test]
+    fn decode_rejects_strings_longer_than_the_configured_max_len() {
+        // STRING, u29=0x1FFFFFFF (inline=1, forged byte length=0x0FFFFFFF).
+        let buf: Vec<u8> = vec![Marker::STRING, 0xFF, 0xFF, 0xFF, 0xFF];
+
+        let mut decoder = Decoder::new(&buf[..]).with_max_string_len(1000);
+        let err = decoder.decode().unwrap_err();
+        assert_eq!(err.kind, super::DecodeErrorKind::StringTooLong { len: 0x0FFF_FFFF });
+    }
+
+    #[test]
+    fn values_iterates_concatenated_values() {
+        let mut buf = Vec::new();
+        Encoder::new(&mut buf).encode(&Value::Double(1.1)).unwrap();
+        Encoder::new(&mut buf)
+            .encode(&Value::String("hi".to_string()))
+            .unwrap();
+
+        let mut decoder = Decoder::new(&buf[..]);
+        let values: super::DecodeResult<Vec<Value>> = decoder.values().collect();
+        assert_eq!(
+            values.unwrap(),
+            vec![Value::Double(1.1), Value::String("hi".to_string())]
+        );
+    }
+
+    #[test]
+    fn values_yields_prior_values_before_failing_on_a_truncated_trailing_value() {
+        let mut buf = Vec::new();
+        Encoder::new(&mut buf).encode(&Value::Double(1.1)).unwrap();
+        buf.push(Marker::STRING); // dangling marker with no payload
+
+        let mut decoder = Decoder::new(&buf[..]);
+        let mut values = decoder.values();
+        assert_eq!(values.next().unwrap().unwrap(), Value::Double(1.1));
+        assert!(values.next().unwrap().is_err());
+        assert!(values.next().is_none());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn value_serde_tags_struct_and_tuple_variants_by_name() {
+        use serde_derive::Serialize;
+        use super::value_serde::to_value;
+
+        #[derive(Serialize)]
+        enum Command {
+            Play { name: String },
+            Seek(f64, f64),
+        }
+
+        let value = to_value(&Command::Play { name: "a".to_string() }).unwrap();
+        assert_eq!(
+            value,
+            Value::Object {
+                name: None,
+                sealed_count: 0,
+                pairs: vec![
+                    Pair {
+                        key: "Play".to_string(),
+                        value: Value::Object {
+                            name: None,
+                            sealed_count: 0,
+                            pairs: vec![
+                                Pair {
+                                    key: "name".to_string(),
+                                    value: Value::String("a".to_string()),
+                                },
+                            ],
+                        },
+                    },
+                ],
+            }
+        );
+
+        let value = to_value(&Command::Seek(1.5, 2.5)).unwrap();
+        assert_eq!(
+            value,
+            Value::Object {
+                name: None,
+                sealed_count: 0,
+                pairs: vec![
+                    Pair {
+                        key: "Seek".to_string(),
+                        value: Value::Array {
+                            assoc_entries: vec![],
+                            dense_entries: vec![Value::Double(1.5), Value::Double(2.5)],
+                        },
+                    },
+                ],
+            }
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn value_serde_round_trips_struct_tuple_and_unit_variants() {
+        use serde_derive::{Serialize, Deserialize};
+        use super::value_serde::{to_value, from_value};
+
+        #[derive(Serialize, Deserialize, PartialEq, Debug)]
+        enum Command {
+            Play { name: String },
+            Seek(f64, f64),
+            Stop,
+        }
+
+        let play = Command::Play { name: "a".to_string() };
+        assert_eq!(from_value::<Command>(to_value(&play).unwrap()).unwrap(), play);
+
+        let seek = Command::Seek(1.5, 2.5);
+        assert_eq!(from_value::<Command>(to_value(&seek).unwrap()).unwrap(), seek);
+
+        let stop = Command::Stop;
+        assert_eq!(from_value::<Command>(to_value(&stop).unwrap()).unwrap(), stop);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn value_serde_to_writer_from_reader_round_trip() {
+        use serde_derive::{Serialize, Deserialize};
+        use super::value_serde::{to_writer, from_reader};
+
+        #[derive(Serialize, Deserialize, PartialEq, Debug)]
+        struct Hoge {
+            index: f64,
+            msg: String,
+        }
+
+        let hoge = Hoge {
+            index: 1_f64,
+            msg: "fuga".to_string(),
+        };
+
+        let mut buf = Vec::new();
+        to_writer(&mut buf, &hoge).unwrap();
+
+        let round_tripped: Hoge = from_reader(&buf[..]).unwrap();
+        assert_eq!(round_tripped, hoge);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn value_serde_to_bytes_from_bytes_round_trip() {
+        use serde_derive::{Serialize, Deserialize};
+        use super::value_serde::{to_bytes, from_bytes};
+
+        #[derive(Serialize, Deserialize, PartialEq, Debug)]
+        struct Hoge {
+            index: f64,
+            msg: String,
+        }
+
+        let hoge = Hoge {
+            index: 1_f64,
+            msg: "fuga".to_string(),
+        };
+
+        let bytes = to_bytes(&hoge).unwrap();
+        let round_tripped: Hoge = from_bytes(&bytes).unwrap();
+        assert_eq!(round_tripped, hoge);
+    }
+
+    #[test]
+    fn read_borrowed_strings_borrow_from_the_source_buffer() {
+        use std::borrow::Cow;
+        use super::{ValueRef, read_borrowed};
+
+        let value = Value::Array {
+            assoc_entries: vec![
+                Pair {
+                    key: "name".to_string(),
+                    value: Value::String("flashver".to_string()),
+                },
+            ],
+            dense_entries: vec![Value::Integer(7)],
+        };
+        let mut buf = Vec::new();
+        Encoder::new(&mut buf).encode(&value).unwrap();
+
+        let borrowed = read_borrowed(&buf).unwrap();
+        match borrowed {
+            ValueRef::Array { ref assoc_entries, ref dense_entries } => {
+                assert_eq!(assoc_entries.len(), 1);
+                assert_eq!(assoc_entries[0].key, Cow::Borrowed("name"));
+                match assoc_entries[0].value {
+                    ValueRef::String(ref s) => {
+                        assert_eq!(*s, Cow::Borrowed("flashver"));
+                        assert!(match *s {
+                            Cow::Borrowed(_) => true,
+                            Cow::Owned(_) => false,
+                        });
+                    }
+                    ref other => panic!("expected a borrowed string, got {:?}", other),
+                }
+                assert_eq!(*dense_entries, vec![ValueRef::Integer(7)]);
+            }
+            ref other => panic!("expected an Array, got {:?}", other),
+        }
+
+        assert_eq!(borrowed.to_owned(), value);
+    }
+
+    #[test]
+    fn read_borrowed_is_unbounded_by_default_but_bounded_via_borrowed_decoder() {
+        use super::{BorrowedDecoder, read_borrowed};
+
+        let value = Value::Array {
+            assoc_entries: vec![],
+            dense_entries: vec![
+                Value::Array { assoc_entries: vec![], dense_entries: vec![Value::Null] },
+            ],
+        };
+        let mut buf = Vec::new();
+        Encoder::new(&mut buf).encode(&value).unwrap();
+
+        assert_eq!(read_borrowed(&buf).unwrap().to_owned(), value);
+
+        let err = BorrowedDecoder::new(&buf).with_max_depth(1).decode().unwrap_err();
+        assert_eq!(err.kind, super::DecodeErrorKind::DepthLimitExceeded { limit: 1 });
+    }
+
+    #[test]
+    fn read_borrowed_rejects_collection_counts_beyond_the_configured_max_len() {
+        use super::BorrowedDecoder;
+
+        // ARRAY, u29=0x1FFFFFFF (object-ref=1, forged dense count=0x0FFFFFFF).
+        let buf: Vec<u8> = vec![Marker::ARRAY, 0xFF, 0xFF, 0xFF, 0xFF];
+
+        let err = BorrowedDecoder::new(&buf).with_max_collection_len(1000).decode().unwrap_err();
+        assert_eq!(err.kind, super::DecodeErrorKind::CollectionTooLarge { len: 0x0FFF_FFFF });
+    }
+
+    #[test]
+    fn encode_integer_promotes_out_of_range_values_to_double() {
+        use super::{MAX_29B_INT, MIN_29B_INT};
+
+        for &n in &[MAX_29B_INT + 1, MIN_29B_INT - 1, i32::max_value(), i32::min_value()] {
+            let mut buf = Vec::new();
+            Encoder::new(&mut buf).encode(&Value::Integer(n)).unwrap();
+            assert_eq!(buf[0], Marker::DOUBLE);
+            assert_eq!(Decoder::new(&buf[..]).decode().unwrap(), Value::Double(n as f64));
+        }
+
+        // Values within the i29 range still round-trip through the compact
+        // integer marker rather than being promoted.
+        for &n in &[0, MAX_29B_INT, MIN_29B_INT] {
+            let mut buf = Vec::new();
+            Encoder::new(&mut buf).encode(&Value::Integer(n)).unwrap();
+            assert_eq!(buf[0], Marker::INTEGER);
+            assert_eq!(Decoder::new(&buf[..]).decode().unwrap(), Value::Integer(n));
+        }
+    }
+
+    #[test]
+    fn decode_error_reports_the_path_to_the_failure() {
+        use super::DecodeErrorKind;
+
+        let value = Value::Object {
+            name: None,
+            sealed_count: 0,
+            pairs: vec![
+                Pair {
+                    key: "metadata".to_string(),
+                    value: Value::Array {
+                        assoc_entries: vec![],
+                        dense_entries: vec![Value::Null, Value::Null, Value::Null],
+                    },
+                },
+            ],
+        };
+        let mut buf = Vec::new();
+        Encoder::new(&mut buf).encode(&value).unwrap();
+
+        // The second-to-last byte encoded is the marker of the third dense
+        // array entry (a bare `Marker::NULL`); the very last byte is the
+        // empty-key terminator of the object's own dynamic field list.
+        // Corrupting the former forces a failure two levels deep, in the
+        // object's "metadata" field at array index 2.
+        let i = buf.len() - 2;
+        buf[i] = 0xFF;
+
+        let err = Decoder::new(&buf[..]).decode().unwrap_err();
+        assert_eq!(err.kind, DecodeErrorKind::UnknownType { marker: 0xFF });
+        assert_eq!(err.path.as_ref().map(String::as_str), Some("$.metadata[2]"));
+    }
 }
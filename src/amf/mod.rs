@@ -1,5 +1,6 @@
 use std::{error, fmt, io, string};
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Version {
     AMF0 = 0x0,
     AMF3 = 0x3,
@@ -11,166 +12,515 @@ pub struct Pair<K, V> {
     pub value: V,
 }
 
+/// A thin `io::Read` adapter that counts the bytes consumed from the
+/// wrapped reader, so a `Decoder` can attach the absolute stream offset of
+/// a decoding failure to its error without threading a counter through
+/// every primitive read by hand.
 #[derive(Debug)]
-pub enum DecodeError {
+pub(crate) struct CountingReader<R> {
+    inner: R,
+    offset: u64,
+}
+
+impl<R> CountingReader<R> {
+    pub(crate) fn new(inner: R) -> Self {
+        CountingReader {
+            inner: inner,
+            offset: 0,
+        }
+    }
+
+    pub(crate) fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub(crate) fn into_inner(self) -> R {
+        self.inner
+    }
+
+    pub(crate) fn inner(&self) -> &R {
+        &self.inner
+    }
+
+    pub(crate) fn inner_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+}
+
+impl<R: io::Read> io::Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = try!(self.inner.read(buf));
+        self.offset += n as u64;
+        Ok(n)
+    }
+}
+
+/// The `io::Write` counterpart of [`CountingReader`], so an `Encoder` can
+/// attach the absolute output offset of an encoding failure to its error.
+#[derive(Debug)]
+pub(crate) struct CountingWriter<W> {
+    inner: W,
+    offset: u64,
+}
+
+impl<W> CountingWriter<W> {
+    pub(crate) fn new(inner: W) -> Self {
+        CountingWriter {
+            inner: inner,
+            offset: 0,
+        }
+    }
+
+    pub(crate) fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub(crate) fn into_inner(self) -> W {
+        self.inner
+    }
+
+    pub(crate) fn inner(&self) -> &W {
+        &self.inner
+    }
+
+    pub(crate) fn inner_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+}
+
+impl<W: io::Write> io::Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = try!(self.inner.write(buf));
+        self.offset += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[derive(Debug)]
+pub enum DecodeErrorKind {
     Io(io::Error),
     String(string::FromUtf8Error),
     NotSupportedType { marker: u8 },
     NotExpectedObjectEnd,
     UnknownType { marker: u8 },
-    NotSupportedReferenceTables { index: usize },
     NotFoundInReferenceTable { index: usize },
     ExternalizableType { name: String },
+    DepthLimitExceeded { limit: usize },
+    CollectionTooLarge { len: usize },
+    StringTooLong { len: usize },
 }
 
-impl fmt::Display for DecodeError {
+impl fmt::Display for DecodeErrorKind {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            DecodeError::Io(ref x) => write!(f, "I/O Error: {}", x),
-            DecodeError::String(ref x) => write!(f, "Invalid String: {}", x),
-            DecodeError::NotSupportedType { marker } => {
+            DecodeErrorKind::Io(ref x) => write!(f, "I/O Error: {}", x),
+            DecodeErrorKind::String(ref x) => write!(f, "Invalid String: {}", x),
+            DecodeErrorKind::NotSupportedType { marker } => {
                 write!(f, "Not supported type: marker={}", marker)
             }
-            DecodeError::NotExpectedObjectEnd => {
+            DecodeErrorKind::NotExpectedObjectEnd => {
                 write!(f, "Not expected occurrence of object-end-marker")
             }
-            DecodeError::UnknownType { marker } => write!(f, "Unknown type: maker={}", marker),
-            DecodeError::NotSupportedReferenceTables { index } => {
-                write!(f, "Reference Tables is not supported: index={}", index)
-            }
-            DecodeError::NotFoundInReferenceTable { index } => {
+            DecodeErrorKind::UnknownType { marker } => write!(f, "Unknown type: maker={}", marker),
+            DecodeErrorKind::NotFoundInReferenceTable { index } => {
                 write!(f, "Value is not found in reference table: index={}", index)
             }
-            DecodeError::ExternalizableType { ref name } => {
+            DecodeErrorKind::ExternalizableType { ref name } => {
                 write!(f, "Externalizable type {:?} is unsupported", name)
             }
+            DecodeErrorKind::DepthLimitExceeded { limit } => {
+                write!(f, "Nesting depth exceeds the configured limit: limit={}", limit)
+            }
+            DecodeErrorKind::CollectionTooLarge { len } => {
+                write!(f, "Collection size exceeds the configured limit: len={}", len)
+            }
+            DecodeErrorKind::StringTooLong { len } => {
+                write!(f, "String length exceeds the configured limit: len={}", len)
+            }
         }
     }
 }
 
-impl error::Error for DecodeError {
-    fn description(&self) -> &str {
+impl error::Error for DecodeErrorKind {
+    fn source(&self) -> Option<&(error::Error + 'static)> {
         match *self {
-            DecodeError::Io(ref x) => x.description(),
-            DecodeError::String(ref x) => x.description(),
-            DecodeError::NotSupportedType { .. } => "Not supported type",
-            DecodeError::NotExpectedObjectEnd { .. } => {
-                "Unexpected occurrence of object-end-marker"
-            }
-            DecodeError::UnknownType { .. } => "Unknown type",
-            DecodeError::NotSupportedReferenceTables { .. } => "Reference Tables is not supported",
-            DecodeError::NotFoundInReferenceTable { .. } => "Value is not found in reference table",
-            DecodeError::ExternalizableType { .. } => "Unsupported externalizable type",
+            DecodeErrorKind::Io(ref x) => Some(x),
+            DecodeErrorKind::String(ref x) => Some(x),
+            _ => None,
+        }
+    }
+}
+
+impl PartialEq for DecodeErrorKind {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (&DecodeErrorKind::UnknownType { marker: x },
+             &DecodeErrorKind::UnknownType { marker: y }) => x == y,
+            (&DecodeErrorKind::NotSupportedType { marker: x },
+             &DecodeErrorKind::NotSupportedType { marker: y }) => x == y,
+            (&DecodeErrorKind::NotExpectedObjectEnd, &DecodeErrorKind::NotExpectedObjectEnd) => true,
+            (&DecodeErrorKind::NotFoundInReferenceTable { index: x },
+             &DecodeErrorKind::NotFoundInReferenceTable { index: y }) => x == y,
+            (&DecodeErrorKind::ExternalizableType { name: ref x },
+             &DecodeErrorKind::ExternalizableType { name: ref y }) => x == y,
+            (&DecodeErrorKind::DepthLimitExceeded { limit: x },
+             &DecodeErrorKind::DepthLimitExceeded { limit: y }) => x == y,
+            (&DecodeErrorKind::CollectionTooLarge { len: x },
+             &DecodeErrorKind::CollectionTooLarge { len: y }) => x == y,
+            (&DecodeErrorKind::StringTooLong { len: x },
+             &DecodeErrorKind::StringTooLong { len: y }) => x == y,
+            _ => false,
         }
     }
+}
+
+impl From<io::Error> for DecodeErrorKind {
+    fn from(f: io::Error) -> Self {
+        DecodeErrorKind::Io(f)
+    }
+}
+
+impl From<string::FromUtf8Error> for DecodeErrorKind {
+    fn from(f: string::FromUtf8Error) -> Self {
+        DecodeErrorKind::String(f)
+    }
+}
+
+/// A single step in a `DecodeError`'s `path`: either an object/map property
+/// or an array/vector element that was being decoded when the error
+/// occurred.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum PathSegment {
+    Key(String),
+    Index(usize),
+}
 
-    fn cause(&self) -> Option<&error::Error> {
+impl fmt::Display for PathSegment {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            DecodeError::Io(ref x) => x.cause(),
-            DecodeError::String(ref x) => x.cause(),
-            _ => None,
+            PathSegment::Key(ref key) => write!(f, ".{}", key),
+            PathSegment::Index(index) => write!(f, "[{}]", index),
         }
     }
 }
 
+fn render_path(path: &[PathSegment]) -> String {
+    let mut s = "$".to_string();
+    for segment in path {
+        s.push_str(&segment.to_string());
+    }
+    s
+}
+
+/// Everything that can go wrong while decoding a value, plus (when the
+/// failure happened while descending into an object property or array
+/// element) the location it happened at, e.g. `$.info.metadata[2]`. The
+/// decoders build this up as they unwind out of nested objects/arrays;
+/// only the innermost (most specific) location is kept.
+#[derive(Debug)]
+pub struct DecodeError {
+    pub kind: DecodeErrorKind,
+    pub path: Option<String>,
+    pub offset: Option<u64>,
+}
+
+impl DecodeError {
+    /// Records `path` as this error's location, unless one is already set
+    /// (the innermost call site to attach a path wins, since it is the most
+    /// specific one).
+    pub(crate) fn with_path(mut self, path: &[PathSegment]) -> Self {
+        if self.path.is_none() && !path.is_empty() {
+            self.path = Some(render_path(path));
+        }
+        self
+    }
+
+    /// Records `offset` as the absolute byte position in the input stream
+    /// where this error occurred, unless one is already set.
+    pub(crate) fn with_offset(mut self, offset: u64) -> Self {
+        if self.offset.is_none() {
+            self.offset = Some(offset);
+        }
+        self
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.path, self.offset) {
+            (&Some(ref path), Some(offset)) => write!(f, "{} at {} (byte {})", self.kind, path, offset),
+            (&Some(ref path), None) => write!(f, "{} at {}", self.kind, path),
+            (&None, Some(offset)) => write!(f, "{} at byte {}", self.kind, offset),
+            (&None, None) => write!(f, "{}", self.kind),
+        }
+    }
+}
+
+impl error::Error for DecodeError {
+    fn source(&self) -> Option<&(error::Error + 'static)> {
+        self.kind.source()
+    }
+}
+
 impl PartialEq for DecodeError {
     fn eq(&self, other: &Self) -> bool {
-        match (self, other) {
-            (&DecodeError::UnknownType { marker: x }, &DecodeError::UnknownType { marker: y }) => {
-                x == y
-            }
-            (&DecodeError::NotSupportedType { marker: x },
-             &DecodeError::NotSupportedType { marker: y }) => x == y,
-            (&DecodeError::NotExpectedObjectEnd, &DecodeError::NotExpectedObjectEnd) => true,
-            (&DecodeError::NotSupportedReferenceTables { index: x },
-             &DecodeError::NotSupportedReferenceTables { index: y }) => x == y,
-            (&DecodeError::NotFoundInReferenceTable { index: x },
-             &DecodeError::NotFoundInReferenceTable { index: y }) => x == y,
-            (&DecodeError::ExternalizableType { name: ref x },
-             &DecodeError::ExternalizableType { name: ref y }) => x == y,
-            _ => false,
+        self.kind == other.kind && self.path == other.path && self.offset == other.offset
+    }
+}
+
+impl From<DecodeErrorKind> for DecodeError {
+    fn from(kind: DecodeErrorKind) -> Self {
+        DecodeError {
+            kind: kind,
+            path: None,
+            offset: None,
         }
     }
 }
 
 impl From<io::Error> for DecodeError {
     fn from(f: io::Error) -> Self {
-        DecodeError::Io(f)
+        DecodeErrorKind::from(f).into()
     }
 }
 
 impl From<string::FromUtf8Error> for DecodeError {
     fn from(f: string::FromUtf8Error) -> Self {
-        DecodeError::String(f)
+        DecodeErrorKind::from(f).into()
     }
 }
 
 pub type DecodeResult<T> = Result<T, DecodeError>;
 
 #[derive(Debug)]
-pub enum EncodeError {
+pub enum EncodeErrorKind {
     Io(io::Error),
     String(string::FromUtf8Error),
     NotSupportedType { marker: u8 },
     U29Overflow { u29: u32 },
+    StringTooLong { len: usize },
+    ArrayTooLong { len: usize },
 }
 
-impl fmt::Display for EncodeError {
+impl fmt::Display for EncodeErrorKind {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            EncodeError::Io(ref x) => write!(f, "I/O Error: {}", x),
-            EncodeError::String(ref x) => write!(f, "Invalid String: {}", x),
-            EncodeError::NotSupportedType { marker } => {
+            EncodeErrorKind::Io(ref x) => write!(f, "I/O Error: {}", x),
+            EncodeErrorKind::String(ref x) => write!(f, "Invalid String: {}", x),
+            EncodeErrorKind::NotSupportedType { marker } => {
                 write!(f, "Not supported type: marker={}", marker)
             }
-            EncodeError::U29Overflow { u29 } => write!(f, "Too large number: u29={}", u29),
+            EncodeErrorKind::U29Overflow { u29 } => write!(f, "Too large number: u29={}", u29),
+            EncodeErrorKind::StringTooLong { len } => write!(f, "Too long string: len={}", len),
+            EncodeErrorKind::ArrayTooLong { len } => write!(f, "Too long array: len={}", len),
         }
     }
 }
 
-impl error::Error for EncodeError {
-    fn description(&self) -> &str {
+impl error::Error for EncodeErrorKind {
+    fn source(&self) -> Option<&(error::Error + 'static)> {
         match *self {
-            EncodeError::Io(ref x) => x.description(),
-            EncodeError::String(ref x) => x.description(),
-            EncodeError::NotSupportedType { .. } => "Not supported type",
-            EncodeError::U29Overflow { .. } => "Too large number",
+            EncodeErrorKind::Io(ref x) => Some(x),
+            EncodeErrorKind::String(ref x) => Some(x),
+            _ => None,
         }
     }
+}
 
-    fn cause(&self) -> Option<&error::Error> {
-        match *self {
-            EncodeError::Io(ref x) => x.cause(),
-            EncodeError::String(ref x) => x.cause(),
-            _ => None,
+impl PartialEq for EncodeErrorKind {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (&EncodeErrorKind::NotSupportedType { marker: x },
+             &EncodeErrorKind::NotSupportedType { marker: y }) => x == y,
+            (&EncodeErrorKind::U29Overflow { u29: x }, &EncodeErrorKind::U29Overflow { u29: y }) => {
+                x == y
+            }
+            (&EncodeErrorKind::StringTooLong { len: x },
+             &EncodeErrorKind::StringTooLong { len: y }) => x == y,
+            (&EncodeErrorKind::ArrayTooLong { len: x },
+             &EncodeErrorKind::ArrayTooLong { len: y }) => x == y,
+            _ => false,
         }
     }
 }
 
+impl From<io::Error> for EncodeErrorKind {
+    fn from(f: io::Error) -> Self {
+        EncodeErrorKind::Io(f)
+    }
+}
+
+impl From<string::FromUtf8Error> for EncodeErrorKind {
+    fn from(f: string::FromUtf8Error) -> Self {
+        EncodeErrorKind::String(f)
+    }
+}
+
+/// Everything that can go wrong while encoding a value, plus the absolute
+/// byte offset into the output stream where the failure occurred, e.g.
+/// "Too long string: len=70000 at byte 482".
+#[derive(Debug)]
+pub struct EncodeError {
+    pub kind: EncodeErrorKind,
+    pub offset: Option<u64>,
+}
+
+impl EncodeError {
+    /// Records `offset` as the absolute byte position in the output stream
+    /// where this error occurred, unless one is already set.
+    pub(crate) fn with_offset(mut self, offset: u64) -> Self {
+        if self.offset.is_none() {
+            self.offset = Some(offset);
+        }
+        self
+    }
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.offset {
+            Some(offset) => write!(f, "{} at byte {}", self.kind, offset),
+            None => write!(f, "{}", self.kind),
+        }
+    }
+}
+
+impl error::Error for EncodeError {
+    fn source(&self) -> Option<&(error::Error + 'static)> {
+        self.kind.source()
+    }
+}
+
 impl PartialEq for EncodeError {
     fn eq(&self, other: &Self) -> bool {
-        match (self, other) {
-            (&EncodeError::NotSupportedType { marker: x },
-             &EncodeError::NotSupportedType { marker: y }) => x == y,
-            (&EncodeError::U29Overflow { u29: x }, &EncodeError::U29Overflow { u29: y }) => x == y,
-            _ => false,
+        self.kind == other.kind && self.offset == other.offset
+    }
+}
+
+impl From<EncodeErrorKind> for EncodeError {
+    fn from(kind: EncodeErrorKind) -> Self {
+        EncodeError {
+            kind: kind,
+            offset: None,
         }
     }
 }
 
 impl From<io::Error> for EncodeError {
     fn from(f: io::Error) -> Self {
-        EncodeError::Io(f)
+        EncodeErrorKind::from(f).into()
     }
 }
 
 impl From<string::FromUtf8Error> for EncodeError {
     fn from(f: string::FromUtf8Error) -> Self {
-        EncodeError::String(f)
+        EncodeErrorKind::from(f).into()
     }
 }
 
 pub type EncodeResult<T> = Result<T, EncodeError>;
 
+// Maps an `f64` onto a `u64` key whose unsigned ordering matches the IEEE
+// 754-2008 §5.10 `totalOrder` predicate: -NaN < -Inf < negatives < -0.0 <
+// +0.0 < positives < +Inf < +NaN. Used to give `amf0::Value`/`amf3::Value`
+// a total `Ord`/`Hash` over their `Number`/`Double` variants.
+pub(crate) fn float_order_key(x: f64) -> u64 {
+    let u = x.to_bits();
+    if u & (1 << 63) != 0 { !u } else { u ^ (1 << 63) }
+}
+
 pub mod amf0;
 pub mod amf3;
+
+/// A crate-level value that wraps either an `amf0::Value` or an
+/// `amf3::Value`, so code that only learns the AMF version at runtime (e.g.
+/// an RTMP command-message handler) can decode/encode a payload without
+/// branching on the concrete submodule type. Mirrors the ergonomics of the
+/// `amf` crate's top-level `Value`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Value {
+    Amf0(amf0::Value),
+    Amf3(amf3::Value),
+}
+
+impl Value {
+    /// Decodes a single value in the given AMF version from `reader`.
+    pub fn read_from<R: io::Read>(reader: R, version: Version) -> DecodeResult<Self> {
+        match version {
+            Version::AMF0 => amf0::Decoder::new(reader).decode().map(Value::Amf0),
+            Version::AMF3 => amf3::Decoder::new(reader).decode().map(Value::Amf3),
+        }
+    }
+
+    /// Encodes this value to `writer`, using whichever AMF version it holds.
+    pub fn write_to<W: io::Write>(&self, writer: W) -> EncodeResult<()> {
+        match *self {
+            Value::Amf0(ref v) => amf0::Encoder::new(writer).encode(v),
+            Value::Amf3(ref v) => amf3::Encoder::new(writer).encode(v),
+        }
+    }
+
+    /// The AMF version this value would be encoded as.
+    pub fn version(&self) -> Version {
+        match *self {
+            Value::Amf0(_) => Version::AMF0,
+            Value::Amf3(_) => Version::AMF3,
+        }
+    }
+}
+
+/// Forwards to whichever inner `amf0::value_serde`/`amf3::value_serde`
+/// bridge applies, so code that holds a version-erased `Value` can still
+/// feed it straight into `serde_json::to_string`, `to_value`, etc. There is
+/// no matching `Deserialize` impl: unlike `amf0::Value`/`amf3::Value`,
+/// which are always decoded with the AMF version known up front via
+/// `read_from`, a bare serde format has no way to tell which submodule's
+/// `Value` it should produce.
+#[cfg(feature = "serde")]
+impl ::serde::Serialize for Value {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: ::serde::Serializer,
+    {
+        match *self {
+            Value::Amf0(ref v) => v.serialize(serializer),
+            Value::Amf3(ref v) => v.serialize(serializer),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Value, Version};
+    use super::amf0;
+    use super::amf3;
+
+    #[test]
+    fn read_write_round_trips_for_each_version() {
+        let value = Value::Amf0(amf0::Value::String("hello".to_string()));
+        let mut buf = Vec::new();
+        value.write_to(&mut buf).unwrap();
+        assert_eq!(value.version(), Version::AMF0);
+        assert_eq!(Value::read_from(&buf[..], Version::AMF0).unwrap(), value);
+
+        let value = Value::Amf3(amf3::Value::Integer(42));
+        let mut buf = Vec::new();
+        value.write_to(&mut buf).unwrap();
+        assert_eq!(value.version(), Version::AMF3);
+        assert_eq!(Value::read_from(&buf[..], Version::AMF3).unwrap(), value);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn version_erased_value_serializes_through_its_inner_value() {
+        use super::amf0::value_serde::to_value as amf0_to_value;
+
+        let inner = amf0::Value::String("hello".to_string());
+        let wrapped = Value::Amf0(inner.clone());
+        assert_eq!(amf0_to_value(&wrapped).unwrap(), amf0_to_value(&inner).unwrap());
+    }
+}